@@ -1,6 +1,7 @@
 use crate::prelude::*;
 pub use scan::RefsScanner;
 pub use sink::Sink;
+pub(crate) use sink::{read_bytes, read_num};
 use std::io::{Read, Write};
 use tee_readwrite::{TeeReader, TeeWriter};
 use unix::sys::stat::Mode;
@@ -198,3 +199,16 @@ pub fn dump_to_bytes<S: io::Read>(size: usize, string: S) -> io::Result<Vec<u8>>
 pub fn restore_path<P: AsRef<Path>, R: Read>(dest: P, source: R) -> Result<()> {
   sink::parse_dump(sink::RestoreSink::new(dest.as_ref()), source)
 }
+
+/// Read the contents of a NAR that holds a single regular file.
+pub fn read_contents<R: Read>(source: R) -> Result<Vec<u8>> {
+  let mut contents = None;
+  sink::parse_dump(sink::ContentsSink(&mut contents), source)?;
+  contents.ok_or_else(|| anyhow!("NAR does not contain a regular file"))
+}
+
+/// Copy a single NAR from `source` to `sink` without unpacking it, reading
+/// no further than the end of the NAR.
+pub fn copy_nar<R: Read, W: Write>(source: R, sink: W) -> Result<()> {
+  sink::parse_dump(sink::NullSink, TeeReader::new(source, sink, false))
+}
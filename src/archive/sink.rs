@@ -190,7 +190,7 @@ fn parse<S: ParseSink, R: Read>(sink: &mut S, source: &mut R, path: Option<PathB
   Ok(())
 }
 
-fn read_num<R: Read>(source: &mut R) -> Result<usize> {
+pub(crate) fn read_num<R: Read>(source: &mut R) -> Result<usize> {
   let mut buf = [0u8; 8];
   source.read_exact(&mut buf)?;
   let result = buf[0] as usize
@@ -226,10 +226,65 @@ fn read_bytes_len<R: Read>(source: &mut R, max: usize) -> Result<Vec<u8>> {
   Ok(bytes)
 }
 
-fn read_bytes<R: Read>(source: &mut R) -> Result<Vec<u8>> {
+pub(crate) fn read_bytes<R: Read>(source: &mut R) -> Result<Vec<u8>> {
   read_bytes_len(source, usize::MAX)
 }
 
+/// Parses a NAR without doing anything with it.
+pub struct NullSink;
+
+impl ParseSink for NullSink {
+  fn create_directory(&mut self, _path: Option<&Path>) -> Result<()> {
+    Ok(())
+  }
+
+  fn create_file(&mut self, _path: Option<&Path>) -> Result<()> {
+    Ok(())
+  }
+
+  fn create_symlink(&mut self, _path: Option<&Path>, _target: PathBuf) -> Result<()> {
+    Ok(())
+  }
+
+  fn set_executable(&mut self) -> Result<()> {
+    Ok(())
+  }
+
+  fn receive_contents<R: Read>(&mut self, mut contents: R) -> Result<()> {
+    io::copy(&mut contents, &mut io::sink())?;
+    Ok(())
+  }
+}
+
+/// Keeps the contents of a NAR that holds a single regular file.
+pub struct ContentsSink<'a>(pub &'a mut Option<Vec<u8>>);
+
+impl ParseSink for ContentsSink<'_> {
+  fn create_directory(&mut self, _path: Option<&Path>) -> Result<()> {
+    bail!("NAR does not contain a regular file")
+  }
+
+  fn create_file(&mut self, path: Option<&Path>) -> Result<()> {
+    ensure!(path.is_none(), "NAR does not contain a regular file");
+    Ok(())
+  }
+
+  fn create_symlink(&mut self, _path: Option<&Path>, _target: PathBuf) -> Result<()> {
+    bail!("NAR does not contain a regular file")
+  }
+
+  fn set_executable(&mut self) -> Result<()> {
+    Ok(())
+  }
+
+  fn receive_contents<R: Read>(&mut self, mut contents: R) -> Result<()> {
+    let mut buf = vec![];
+    contents.read_to_end(&mut buf)?;
+    *self.0 = Some(buf);
+    Ok(())
+  }
+}
+
 pub struct RestoreSink {
   root: PathBuf,
   last_file: Option<File>,
@@ -270,8 +325,10 @@ impl ParseSink for RestoreSink {
     Ok(())
   }
 
-  fn create_symlink(&mut self, _path: Option<&Path>, _target: PathBuf) -> Result<()> {
-    todo!()
+  fn create_symlink(&mut self, path: Option<&Path>, target: PathBuf) -> Result<()> {
+    debug!("creating symlink {}", self.get_path(path).display());
+    std::os::unix::fs::symlink(target, self.get_path(path))?;
+    Ok(())
   }
 
   fn set_executable(&mut self) -> Result<()> {
@@ -1,32 +1,381 @@
-use rix::{store::*, util::*};
-use std::{path::PathBuf, sync::Arc};
+use rix::{
+  settings::Settings,
+  signing::SecretKey,
+  store::{gc::*, *},
+  util::*,
+};
+use std::{
+  collections::BTreeSet,
+  fs,
+  io::{self, Write},
+  os::unix::fs::OpenOptionsExt,
+  path::PathBuf,
+};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
 enum Op {
   #[structopt(name = "--realise", alias = "-r")]
   Realise {
+    #[structopt(
+      long,
+      help = "Rebuild the given derivations and check that the results are identical to their \
+              existing outputs."
+    )]
+    check: bool,
     #[structopt(parse(from_os_str))]
     buildables: Vec<PathBuf>,
   },
+  #[structopt(name = "--query", alias = "-q")]
+  Query {
+    #[structopt(long, help = "Print the immediate references of the given paths.")]
+    references: bool,
+    #[structopt(
+      long,
+      help = "Print the paths that immediately refer to the given paths."
+    )]
+    referrers: bool,
+    #[structopt(long, help = "Print the outputs of the given derivations.")]
+    outputs: bool,
+    #[structopt(
+      long = "valid-derivers",
+      help = "Print the valid derivations that produce the given paths."
+    )]
+    valid_derivers: bool,
+    #[structopt(long, short = "R", help = "Print the closure of the given paths.")]
+    requisites: bool,
+    #[structopt(
+      long = "referrers-closure",
+      help = "Print the paths that refer to the given paths, directly or indirectly."
+    )]
+    referrers_closure: bool,
+    #[structopt(long, help = "Print the closure of the given paths as a tree.")]
+    tree: bool,
+    #[structopt(
+      long,
+      help = "Print the closure of the given paths in Graphviz format."
+    )]
+    graph: bool,
+    #[structopt(long, help = "Print the closure of the given paths in GraphML format.")]
+    graphml: bool,
+    #[structopt(
+      long = "include-outputs",
+      help = "Also include the outputs of derivations in the closure."
+    )]
+    include_outputs: bool,
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+  },
+  #[structopt(name = "--gc")]
+  Gc {
+    #[structopt(
+      long = "print-roots",
+      help = "Print the roots of the garbage collector."
+    )]
+    print_roots: bool,
+    #[structopt(
+      long = "print-live",
+      conflicts_with = "print-roots",
+      help = "Print the paths that are reachable from a root."
+    )]
+    print_live: bool,
+    #[structopt(
+      long = "print-dead",
+      conflicts_with_all = &["print-roots", "print-live"],
+      help = "Print the paths that would be deleted."
+    )]
+    print_dead: bool,
+    #[structopt(
+      long = "max-freed",
+      help = "Stop after freeing this many bytes (accepts K, M, G and T suffixes).",
+      parse(try_from_str = parse_size)
+    )]
+    max_freed: Option<u64>,
+  },
+  #[structopt(name = "--delete")]
+  Delete {
+    #[structopt(
+      long = "ignore-liveness",
+      help = "Delete the paths even if they are reachable from a root."
+    )]
+    ignore_liveness: bool,
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+  },
+  #[structopt(name = "--verify")]
+  Verify {
+    #[structopt(
+      long = "check-contents",
+      help = "Check that the contents of each path match the hash in the database."
+    )]
+    check_contents: bool,
+    #[structopt(
+      long,
+      help = "Repair missing or corrupted paths by substituting or rebuilding them."
+    )]
+    repair: bool,
+  },
+  #[structopt(name = "--export")]
+  Export {
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+  },
+  #[structopt(name = "--import")]
+  Import,
+  #[structopt(name = "--optimise", alias = "--optimize")]
+  Optimise,
+  #[structopt(name = "--generate-binary-cache-key")]
+  GenerateBinaryCacheKey {
+    #[structopt(help = "Name of the key, conventionally of the form cache.example.org-1.")]
+    key_name: String,
+    #[structopt(parse(from_os_str))]
+    secret_key_file: PathBuf,
+    #[structopt(parse(from_os_str))]
+    public_key_file: PathBuf,
+  },
+}
+
+fn parse_size(s: &str) -> Result<u64> {
+  let (digits, multiplier) = match s.chars().last() {
+    Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+    Some('M') => (&s[..s.len() - 1], 1 << 20),
+    Some('G') => (&s[..s.len() - 1], 1 << 30),
+    Some('T') => (&s[..s.len() - 1], 1 << 40),
+    _ => (s, 1),
+  };
+  digits
+    .parse::<u64>()?
+    .checked_mul(multiplier)
+    .ok_or_else(|| anyhow!("size `{}' is too large", s))
+}
+
+fn print_freed(results: &GcResults) {
+  println!(
+    "{} store paths deleted, {:.2} MiB freed",
+    results.paths.len(),
+    results.bytes_freed as f64 / (1024.0 * 1024.0)
+  );
 }
 
 fn main() -> Result<()> {
+  rix::globals::init()?;
+  Settings::init();
+
   let args = Op::from_args();
 
   match args {
-    Op::Realise { buildables } => {
-      let store = Arc::new(LocalStore::open()?);
+    Op::Realise { check, buildables } => {
+      let store = open_store()?;
       let targets = buildables
         .into_iter()
         .map(|path| {
           Ok(rix::path::PathWithOutputs {
-            path: store.parse_store_path(path.canonicalize()?)?,
+            path: store.follow_links_to_store_path(&path)?,
             outputs: Default::default(),
           })
         })
         .collect::<Result<Vec<_>>>()?;
-      store.build_paths(targets)
+      if check {
+        store.check_paths(targets)
+      } else {
+        store.build_paths(targets)
+      }
+    }
+    Op::Query {
+      references,
+      referrers,
+      // --outputs is the default query
+      outputs: _,
+      valid_derivers,
+      requisites,
+      referrers_closure,
+      tree,
+      graph,
+      graphml,
+      include_outputs,
+      paths,
+    } => {
+      let store = LocalStore::open()?;
+      let paths = paths
+        .iter()
+        .map(|p| store.follow_links_to_store_path(p))
+        .collect::<Result<BTreeSet<_>>>()?;
+      let stdout = io::stdout();
+      let mut out = stdout.lock();
+
+      if tree {
+        let mut done = BTreeSet::new();
+        for p in &paths {
+          graph::print_tree(&store, &mut out, p, "", "", &mut done)?;
+        }
+        return Ok(());
+      } else if graph {
+        return graph::print_dot_graph(&store, &mut out, paths);
+      } else if graphml {
+        return graph::print_graphml(&store, &mut out, paths);
+      }
+
+      let mut result = BTreeSet::new();
+      for p in &paths {
+        if references {
+          let info = store
+            .get_path_info(p)?
+            .ok_or_else(|| anyhow!("path {} is not valid", store.print_store_path(p)))?;
+          result.extend(info.references().iter().cloned());
+        } else if referrers {
+          result.extend(store.query_referrers(p)?);
+        } else if valid_derivers {
+          result.extend(store.query_valid_derivers(p)?);
+        } else if requisites || referrers_closure {
+          let options = ClosureOpts {
+            backwards: referrers_closure,
+            include_outputs,
+            ..Default::default()
+          };
+          store.compute_closure(p, &mut result, options)?;
+        } else {
+          result.extend(
+            store
+              .query_derivation_outputs(p)?
+              .into_iter()
+              .map(|(_, p)| p),
+          );
+        }
+      }
+
+      // dependencies first
+      for p in store.topo_sort_paths(&result)?.iter().rev() {
+        writeln!(out, "{}", store.print_store_path(p))?;
+      }
+
+      Ok(())
+    }
+    Op::Gc {
+      print_roots,
+      print_live,
+      print_dead,
+      max_freed,
+    } => {
+      let store = LocalStore::open()?;
+
+      if print_roots {
+        let mut roots = BTreeSet::new();
+        for (target, links) in store.find_roots(false)? {
+          for link in links {
+            roots.insert((link, target.clone()));
+          }
+        }
+        for (link, target) in roots {
+          println!("{} -> {}", link.display(), store.print_store_path(&target));
+        }
+        return Ok(());
+      }
+
+      let options = GcOptions {
+        action: if print_live {
+          GcAction::ReturnLive
+        } else if print_dead {
+          GcAction::ReturnDead
+        } else {
+          GcAction::DeleteDead
+        },
+        max_freed: max_freed.unwrap_or(u64::MAX),
+        ..Default::default()
+      };
+
+      let results = store.collect_garbage(&options)?;
+
+      if options.action == GcAction::DeleteDead {
+        print_freed(&results);
+      } else {
+        for path in &results.paths {
+          println!("{}", path);
+        }
+      }
+
+      Ok(())
+    }
+    Op::Delete {
+      ignore_liveness,
+      paths,
+    } => {
+      let store = LocalStore::open()?;
+      let options = GcOptions {
+        action: GcAction::DeleteSpecific,
+        ignore_liveness,
+        paths_to_delete: paths
+          .iter()
+          .map(|p| store.parse_store_path(p))
+          .collect::<Result<_>>()?,
+        ..Default::default()
+      };
+      print_freed(&store.collect_garbage(&options)?);
+      Ok(())
+    }
+    Op::Verify {
+      check_contents,
+      repair,
+    } => {
+      let store = LocalStore::open()?;
+      let repair = if repair {
+        RepairFlag::Repair
+      } else {
+        RepairFlag::NoRepair
+      };
+      if store.verify_store(check_contents, repair)? {
+        bail!("not all errors were fixed");
+      }
+      Ok(())
+    }
+    Op::Export { paths } => {
+      let store = LocalStore::open()?;
+      let paths = paths
+        .iter()
+        .map(|p| store.follow_links_to_store_path(p))
+        .collect::<Result<BTreeSet<_>>>()?;
+      let stdout = io::stdout();
+      let mut out = io::BufWriter::new(stdout.lock());
+      export_paths(&store, &paths, &mut out)?;
+      out.flush()?;
+      Ok(())
+    }
+    Op::Import => {
+      let store = LocalStore::open()?;
+      let stdin = io::stdin();
+      let paths = import_paths(
+        &store,
+        io::BufReader::new(stdin.lock()),
+        CheckSigsFlag::NoCheckSigs,
+      )?;
+      for p in paths {
+        println!("{}", store.print_store_path(&p));
+      }
+      Ok(())
+    }
+    Op::Optimise => {
+      let stats = LocalStore::open()?.optimise_store()?;
+      println!(
+        "{:.2} MiB freed by hard-linking {} files",
+        stats.bytes_freed as f64 / (1024.0 * 1024.0),
+        stats.files_linked
+      );
+      Ok(())
+    }
+    Op::GenerateBinaryCacheKey {
+      key_name,
+      secret_key_file,
+      public_key_file,
+    } => {
+      let key = SecretKey::generate(&key_name)?;
+      fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&secret_key_file)?
+        .write_all(key.to_string().as_bytes())?;
+      fs::write(&public_key_file, key.to_public_key().to_string())?;
+      Ok(())
     }
   }
 }
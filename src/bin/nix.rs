@@ -0,0 +1,311 @@
+#[macro_use] extern crate slog_scope;
+
+use rix::{
+  hash::{Encoding, Sink},
+  path_info::{path_info_json, PathInfo},
+  settings,
+  settings::{CliOptions, Settings},
+  signing::{self, SecretKey},
+  store::{self, open_store, open_store_uri, CheckSigsFlag},
+  util::*,
+  Store,
+};
+use std::{collections::BTreeSet, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+struct Args {
+  #[structopt(subcommand)]
+  command: Command,
+  #[structopt(flatten)]
+  other_opts: CliOptions,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+  #[structopt(about = "Copy paths and their closures to another store.")]
+  Copy {
+    #[structopt(
+      long,
+      help = "URI of the store to copy from, e.g. file:///var/cache/nix. Defaults to the \
+              `store' setting."
+    )]
+    from: Option<String>,
+    #[structopt(
+      long,
+      help = "URI of the store to copy to, e.g. file:///var/cache/nix or daemon. Defaults to \
+              the `store' setting."
+    )]
+    to: Option<String>,
+    #[structopt(
+      long = "no-check-sigs",
+      help = "Don't require that the copied paths are signed by a trusted key."
+    )]
+    no_check_sigs: bool,
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+  },
+  #[structopt(
+    name = "sign-paths",
+    about = "Sign the given paths and add the signatures to the store."
+  )]
+  SignPaths {
+    #[structopt(
+      long = "key-file",
+      short = "k",
+      parse(from_os_str),
+      help = "File containing the secret signing key."
+    )]
+    key_file: PathBuf,
+    #[structopt(
+      long,
+      short = "r",
+      help = "Apply the operation to the closures of the given paths."
+    )]
+    recursive: bool,
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+  },
+  #[structopt(
+    name = "path-info",
+    about = "Show information about the given paths, such as their sizes and signatures."
+  )]
+  PathInfo {
+    #[structopt(long, help = "Print the information as JSON.")]
+    json: bool,
+    #[structopt(long, short = "s", help = "Print the size of the NAR of each path.")]
+    size: bool,
+    #[structopt(
+      long = "closure-size",
+      short = "S",
+      help = "Print the total NAR size of the closure of each path."
+    )]
+    closure_size: bool,
+    #[structopt(long, help = "Print the signatures of each path.")]
+    sigs: bool,
+    #[structopt(
+      long,
+      short = "r",
+      help = "Apply the operation to the closures of the given paths."
+    )]
+    recursive: bool,
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+  },
+  #[structopt(about = "Verify the contents and signatures of the given paths.")]
+  Verify {
+    #[structopt(long = "no-contents", help = "Don't check the contents of the paths.")]
+    no_contents: bool,
+    #[structopt(long = "no-trust", help = "Don't check the signatures of the paths.")]
+    no_trust: bool,
+    #[structopt(
+      long = "sigs-needed",
+      short = "n",
+      default_value = "1",
+      help = "Require this many valid signatures for each path."
+    )]
+    sigs_needed: usize,
+    #[structopt(
+      long,
+      short = "r",
+      help = "Apply the operation to the closures of the given paths."
+    )]
+    recursive: bool,
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+  },
+}
+
+fn store_paths<S: Store>(
+  store: &S,
+  paths: &[PathBuf],
+  recursive: bool,
+) -> Result<BTreeSet<rix::path::Path>> {
+  let mut result = BTreeSet::new();
+  for p in paths {
+    // paths in stores that live elsewhere, like binary caches, don't exist here
+    let path = if p.exists() {
+      store.to_store_path(&p.canonicalize()?)?
+    } else {
+      store.to_store_path(p)?
+    };
+    if recursive {
+      store.compute_closure(&path, &mut result, Default::default())?;
+    } else {
+      result.insert(path);
+    }
+  }
+  Ok(result)
+}
+
+fn closure_size_of<S: Store>(store: &S, path: &rix::path::Path) -> Result<u64> {
+  let mut closure = BTreeSet::new();
+  store.compute_closure(path, &mut closure, Default::default())?;
+  let mut size = 0;
+  for p in &closure {
+    if let Some(info) = store.get_path_info(p)? {
+      size += info.nar_size().unwrap_or(0);
+    }
+  }
+  Ok(size)
+}
+
+fn main() -> Result<()> {
+  rix::globals::init()?;
+
+  let args = Args::from_args();
+
+  Settings::init_with_args(args.other_opts);
+
+  match args.command {
+    Command::Copy {
+      from,
+      to,
+      no_check_sigs,
+      paths,
+    } => {
+      ensure!(
+        from.is_some() || to.is_some(),
+        "you must specify a store to copy from or to with --from or --to"
+      );
+      let src = open_store_uri(from.as_ref().unwrap_or(&settings().store_uri))?;
+      let dst = open_store_uri(to.as_ref().unwrap_or(&settings().store_uri))?;
+      let paths = store_paths(&src, &paths, false)?;
+      let check_sigs = if no_check_sigs {
+        CheckSigsFlag::NoCheckSigs
+      } else {
+        CheckSigsFlag::CheckSigs
+      };
+      store::copy_paths(&src, &dst, &paths, check_sigs)
+    }
+    Command::SignPaths {
+      key_file,
+      recursive,
+      paths,
+    } => {
+      let store = open_store()?;
+      let key = SecretKey::read(&key_file)?;
+      let mut added = 0;
+
+      for path in store_paths(&store, &paths, recursive)? {
+        let mut info = store
+          .get_path_info(&path)?
+          .ok_or_else(|| anyhow!("path {} is not valid", store.print_store_path(&path)))?
+          .to_valid_path_info();
+        let old_sigs = info.signatures.clone();
+        signing::sign_path_info(&store, &mut info, &key)?;
+        if info.signatures != old_sigs {
+          store.add_signatures(&path, &info.signatures)?;
+          added += 1;
+        }
+      }
+
+      println!("added {} signatures", added);
+      Ok(())
+    }
+    Command::PathInfo {
+      json,
+      size,
+      closure_size: show_closure_size,
+      sigs,
+      recursive,
+      paths,
+    } => {
+      let store = open_store()?;
+      let paths = store_paths(&store, &paths, recursive)?;
+      let width = paths
+        .iter()
+        .map(|p| store.print_store_path(p).len())
+        .max()
+        .unwrap_or(0);
+      let mut infos = vec![];
+
+      for path in &paths {
+        let info = store
+          .get_path_info(path)?
+          .ok_or_else(|| anyhow!("path {} is not valid", store.print_store_path(path)))?;
+        let closure_size = if show_closure_size {
+          Some(closure_size_of(&store, path)?)
+        } else {
+          None
+        };
+
+        if json {
+          infos.push(path_info_json(&store, &*info, closure_size)?);
+          continue;
+        }
+
+        let mut line = format!("{:<width$}", store.print_store_path(path), width = width);
+        if size {
+          line.push_str(&format!("\t{:>11}", info.nar_size().unwrap_or(0)));
+        }
+        if let Some(c) = closure_size {
+          line.push_str(&format!("\t{:>11}", c));
+        }
+        if sigs {
+          line.push('\t');
+          line.push_str(&itertools::join(info.signatures(), " "));
+        }
+        println!("{}", line);
+      }
+
+      if json {
+        println!("{}", serde_json::Value::Array(infos));
+      }
+      Ok(())
+    }
+    Command::Verify {
+      no_contents,
+      no_trust,
+      sigs_needed,
+      recursive,
+      paths,
+    } => {
+      let store = open_store()?;
+      let keys = signing::trusted_public_keys()?;
+      let (mut corrupted, mut untrusted) = (0, 0);
+
+      for path in store_paths(&store, &paths, recursive)? {
+        let info = store
+          .get_path_info(&path)?
+          .ok_or_else(|| anyhow!("path {} is not valid", store.print_store_path(&path)))?;
+
+        if !no_contents {
+          let mut sink = Sink::new(info.nar_hash().type_());
+          store.nar_from_path(&path, &mut sink)?;
+          let (hash, _) = sink.finish();
+          if &hash != info.nar_hash() {
+            error!(
+              "path {} was modified! expected hash {}, got {}",
+              store.print_store_path(&path),
+              info.nar_hash().encode_with_type(Encoding::Base32),
+              hash.encode_with_type(Encoding::Base32)
+            );
+            corrupted += 1;
+          }
+        }
+
+        if !no_trust {
+          let valid = if signing::is_content_addressed(&store, &*info)? {
+            sigs_needed
+          } else {
+            signing::check_signatures(&store, &*info, &keys)?
+          };
+          if valid < sigs_needed {
+            error!("path {} is untrusted", store.print_store_path(&path));
+            untrusted += 1;
+          }
+        }
+      }
+
+      if corrupted > 0 || untrusted > 0 {
+        bail!(
+          "{} paths were modified and {} paths are untrusted",
+          corrupted,
+          untrusted
+        );
+      }
+      Ok(())
+    }
+  }
+}
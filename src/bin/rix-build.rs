@@ -11,6 +11,12 @@ use structopt::StructOpt;
 struct Args {
   #[structopt(short = "E", long = "eval")]
   expr: Option<String>,
+  #[structopt(
+    long,
+    help = "Rebuild the given derivations and check that the results are identical to their \
+            existing outputs."
+  )]
+  check: bool,
   #[structopt(name = "DRV-OR-FILE", multiple = true)]
   buildables: Vec<String>,
   #[structopt(flatten)]
@@ -66,7 +72,11 @@ fn main() -> Result<()> {
     bail!("no build targets given on command line")
   }
 
-  eval.store.build_paths(build_targets)?;
+  if args.check {
+    eval.store.check_paths(build_targets)?;
+  } else {
+    eval.store.build_paths(build_targets)?;
+  }
 
   Ok(())
 }
@@ -0,0 +1,134 @@
+#[macro_use] extern crate slog_scope;
+
+use rix::{
+  daemon::server,
+  settings,
+  settings::{CliOptions, Settings},
+  store::LocalStore,
+  util::*,
+};
+use std::{
+  fs,
+  io::{BufReader, BufWriter},
+  os::unix::{
+    fs::PermissionsExt,
+    io::AsRawFd,
+    net::{UnixListener, UnixStream},
+  },
+};
+use structopt::StructOpt;
+use unix::{
+  sys::signal::{signal, SigHandler, Signal},
+  unistd::{close, fork, ForkResult, Gid, Uid},
+};
+
+#[derive(StructOpt)]
+#[structopt(about = "Serve the Nix store to other users over a Unix domain socket.")]
+struct Args {
+  #[structopt(flatten)]
+  other_opts: CliOptions,
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(conn: &UnixStream) -> Result<(u32, u32)> {
+  use unix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+  let creds = getsockopt(conn.as_raw_fd(), PeerCredentials)?;
+  Ok((creds.uid(), creds.gid()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(_: &UnixStream) -> Result<(u32, u32)> {
+  bail!("can't determine the user on the other end of the socket on this platform")
+}
+
+fn serve(conn: UnixStream, uid: u32, gid: u32, trusted: bool) -> Result<()> {
+  let mut store = LocalStore::open()?;
+  // kept build directories go to the client rather than the daemon's user
+  store.set_requester(Uid::from_raw(uid), Gid::from_raw(gid));
+  let from = BufReader::new(conn.try_clone()?);
+  server::process_connection(&store, from, BufWriter::new(conn), trusted)
+}
+
+fn accept(listener: &UnixListener, conn: UnixStream) -> Result<()> {
+  let (uid, gid) = peer_credentials(&conn)?;
+  let user = users::get_user_by_uid(uid).map_or_else(
+    || uid.to_string(),
+    |u| u.name().to_string_lossy().into_owned(),
+  );
+  let groups = users::get_user_groups(&user, gid)
+    .unwrap_or_default()
+    .iter()
+    .map(|g| g.name().to_string_lossy().into_owned())
+    .collect::<Vec<_>>();
+
+  let settings = settings();
+  let trusted = uid == 0 || server::matches_user(&settings.trusted_users, &user, &groups);
+  if !trusted && !server::matches_user(&settings.allowed_users, &user, &groups) {
+    bail!(
+      "user `{}' is not allowed to connect to the Nix daemon",
+      user
+    );
+  }
+
+  info!(
+    "accepted connection from user {}{}",
+    user,
+    if trusted { " (trusted)" } else { "" }
+  );
+
+  // every client gets its own process, which keeps their stores, temp roots
+  // and log output apart
+  match unsafe { fork() }? {
+    ForkResult::Parent { .. } => Ok(()),
+    ForkResult::Child => {
+      let _ = close(listener.as_raw_fd());
+      // builds need to be able to wait for their children
+      unsafe { signal(Signal::SIGCHLD, SigHandler::SigDfl) }?;
+      let status = match serve(conn, uid, gid, trusted) {
+        Ok(()) => 0,
+        Err(e) => {
+          error!("{:#}", e);
+          1
+        }
+      };
+      std::process::exit(status)
+    }
+  }
+}
+
+fn main() -> Result<()> {
+  rix::globals::init()?;
+
+  let args = Args::from_args();
+
+  Settings::init_with_args(args.other_opts);
+
+  let settings = settings();
+  let socket_path = &settings.paths.nix_daemon_socket_file;
+  if let Some(parent) = socket_path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  // left behind by a daemon that didn't shut down cleanly
+  delete_path(socket_path)?;
+
+  let listener = UnixListener::bind(socket_path)
+    .with_context(|| format!("cannot bind to socket `{}'", socket_path.display()))?;
+  // access control happens once a client connects
+  fs::set_permissions(socket_path, fs::Permissions::from_mode(0o666))?;
+
+  // connection processes are never waited for
+  unsafe { signal(Signal::SIGCHLD, SigHandler::SigIgn) }?;
+
+  info!("listening on {}", socket_path.display());
+
+  for conn in listener.incoming() {
+    let result = conn
+      .map_err(anyhow::Error::from)
+      .and_then(|conn| accept(&listener, conn));
+    if let Err(e) = result {
+      error!("error processing connection: {:#}", e);
+    }
+  }
+
+  Ok(())
+}
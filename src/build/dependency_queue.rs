@@ -114,7 +114,11 @@ impl<N: Hash + Eq + Clone, E: Eq + Hash + Clone, V> DependencyQueue<N, E, V> {
     let key = (node.clone(), edge.clone());
     let mut result = Vec::new();
     for dep in reverse_deps.iter() {
-      let edges = &mut self.dep_map.get_mut(dep).unwrap().0;
+      // dependents of a failed node have been removed already
+      let edges = match self.dep_map.get_mut(dep) {
+        Some((edges, _)) => edges,
+        None => continue,
+      };
       assert!(edges.remove(&key));
       if edges.is_empty() {
         result.push(dep);
@@ -122,4 +126,54 @@ impl<N: Hash + Eq + Clone, E: Eq + Hash + Clone, V> DependencyQueue<N, E, V> {
     }
     result
   }
+
+  /// Remove everything that depends on `node`, directly or not, since it
+  /// can't be built now that `node` failed. Returns the removed nodes.
+  pub fn fail(&mut self, node: &N) -> Vec<N> {
+    let mut removed = Vec::new();
+    let mut stack = vec![node.clone()];
+    while let Some(n) = stack.pop() {
+      for dep in self
+        .reverse_dep_map
+        .get(&n)
+        .into_iter()
+        .flat_map(|it| it.values())
+        .flatten()
+      {
+        if self.dep_map.remove(dep).is_some() {
+          removed.push(dep.clone());
+          stack.push(dep.clone());
+        }
+      }
+    }
+    removed
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fail() {
+    let mut queue = DependencyQueue::new();
+    queue.enqueue("a", (), vec![]);
+    queue.enqueue("b", (), vec![]);
+    queue.enqueue("c", (), vec![("a", "out")]);
+    queue.enqueue("d", (), vec![("c", "out"), ("b", "out")]);
+    queue.enqueue("e", (), vec![("b", "out")]);
+    queue.queue_finished();
+
+    let mut ready = vec![queue.dequeue().unwrap().0, queue.dequeue().unwrap().0];
+    ready.sort_unstable();
+    assert_eq!(ready, vec!["a", "b"]);
+
+    let mut failed = queue.fail(&"a");
+    failed.sort_unstable();
+    assert_eq!(failed, vec!["c", "d"]);
+
+    assert_eq!(queue.finish(&"b", &"out"), vec![&"e"]);
+    assert_eq!(queue.dequeue().map(|x| x.0), Some("e"));
+    assert!(queue.is_empty());
+  }
 }
@@ -1,6 +1,7 @@
-use std::{os::unix::fs::symlink, ptr};
+use std::{cell::Cell, os::unix::fs::symlink, ptr, thread, time::Instant};
 
 use ipc_channel::ipc::{self, IpcReceiver};
+use parking_lot::Mutex;
 use unix::{
   mount::*,
   sched::{clone, unshare, CloneFlags},
@@ -13,8 +14,10 @@ use unix::{
 };
 
 use crate::{
+  path_info::fixed_output_ca,
   store::ClosureOpts,
   sync::{fs_lock::PathLocks, user_lock::UserLock},
+  util::pid::Pid,
 };
 
 use super::*;
@@ -22,6 +25,7 @@ use super::*;
 const SANDBOX_UID: u32 = 1000;
 const SANDBOX_GID: u32 = 100;
 
+#[allow(clippy::too_many_arguments)]
 pub(super) fn exec_builder<S: Store>(
   store: &S,
   messages: &Arc<Queue<Message>>,
@@ -29,7 +33,10 @@ pub(super) fn exec_builder<S: Store>(
   path: &StorePath,
   drv: &Derivation,
   progress: &Arc<MultiProgress>,
+  check: bool,
+  requester: Option<(Uid, Gid)>,
 ) -> Result<Option<FinishedChild>> {
+  let settings = settings();
   let build_log_path = store.logfile_of(path);
   std::fs::create_dir_all(build_log_path.parent().unwrap())?;
 
@@ -60,20 +67,55 @@ pub(super) fn exec_builder<S: Store>(
     .out_paths()
     .map(|s| store.to_real_path(s))
     .collect::<Result<Vec<_>>>()?;
-  let _path_locks = PathLocks::new().lock(&lock_files, true, None)?;
+  let mut path_locks = PathLocks::new();
+  path_locks.lock(&lock_files, true, None)?;
 
-  let build_user = match settings().build_users_group {
+  let build_user = match settings.build_users_group {
     Some(ref u) => UserLock::get_free_user(u)?,
     None => None,
   };
 
+  let chroot_root_dir = store.to_real_path(path)?.with_extension("drv.chroot");
+  rm_rf(&chroot_root_dir)?;
+
+  // removed by the guard below, since failed builds may need to keep it
   let builder_tmp = tempfile::Builder::new()
     .prefix(format!("nix-build-{}-", drv.name).as_str())
-    .tempdir()?;
+    .tempdir()?
+    .into_path();
+
+  let succeeded = Cell::new(false);
+  let _cleanup = RunOnDrop::new(|| {
+    let dirs = [&builder_tmp, &chroot_root_dir];
+    if !succeeded.get() && settings.keep_failed {
+      // the chroot may not have been set up yet
+      for dir in dirs.iter().filter(|d| d.exists()) {
+        // files written by a build user or the daemon belong to whoever asked
+        // for the build
+        if build_user.is_some() || requester.is_some() {
+          let (uid, gid) = requester.unwrap_or_else(|| (unistd::getuid(), unistd::getgid()));
+          if let Err(e) = chown_r(dir, uid, gid) {
+            warn!("unable to change ownership of `{}': {:?}", dir.display(), e);
+          }
+        }
+        warn!("note: keeping build directory `{}'", dir.display());
+      }
+    } else {
+      for dir in &dirs {
+        if let Err(e) = rm_rf(dir) {
+          warn!(
+            "unable to clean up build directory `{}': {:?}",
+            dir.display(),
+            e
+          );
+        }
+      }
+    }
+  });
 
-  let mut dirs_in_chroot = settings()
+  let mut dirs_in_chroot = settings
     .sandbox_paths
-    .union(&settings().extra_sandbox_paths)
+    .union(&settings.extra_sandbox_paths)
     .map(|dir| {
       let mut dir = dir.as_str();
       let mut optional = false;
@@ -95,8 +137,8 @@ pub(super) fn exec_builder<S: Store>(
     .collect::<HashMap<_, _>>();
 
   dirs_in_chroot.insert(
-    Cow::Borrowed(settings().sandbox_build_dir.as_ref()),
-    (Cow::Borrowed(builder_tmp.as_ref()), false),
+    Cow::Borrowed(settings.sandbox_build_dir.as_ref()),
+    (Cow::Borrowed(builder_tmp.as_path()), false),
   );
 
   let mut extra_dirs_closure = BTreeSet::new();
@@ -108,20 +150,14 @@ pub(super) fn exec_builder<S: Store>(
     }
   }
 
+  // store paths are mounted at their logical location, wherever they really
+  // live
   for obj in extra_dirs_closure {
-    let dest = store.to_real_path(&obj)?;
-    dirs_in_chroot.insert(Cow::Owned(dest.clone()), (Cow::Owned(dest), false));
+    let dest = PathBuf::from(store.print_store_path(&obj));
+    let source = store.to_real_path(&obj)?;
+    dirs_in_chroot.insert(Cow::Owned(dest), (Cow::Owned(source), false));
   }
 
-  let chroot_root_dir = store.to_real_path(path)?.with_extension("drv.chroot");
-
-  let _ = RunOnDrop::new(|| {
-    if let Err(e) = rm_rf(&chroot_root_dir) {
-      warn!("unable to cleanup chroot directory: {:?}", e);
-    }
-  });
-
-  rm_rf(&chroot_root_dir)?;
   debug!(
     "setting up chroot environment in {}",
     chroot_root_dir.display()
@@ -136,7 +172,7 @@ pub(super) fn exec_builder<S: Store>(
        user:{2}:/noshell\nnobody:x:65534:65534:Nobody:/:/noshell\n",
       SANDBOX_UID,
       SANDBOX_GID,
-      settings().sandbox_build_dir.display()
+      settings.sandbox_build_dir.display()
     ),
   )?;
   fs::write(
@@ -161,23 +197,19 @@ pub(super) fn exec_builder<S: Store>(
         real_path.display()
       );
     }
+    let logical_path = PathBuf::from(store.print_store_path(p));
     if real_path.is_dir() {
-      dirs_in_chroot.insert(
-        Cow::Owned(real_path.clone()),
-        (Cow::Owned(real_path), false),
-      );
+      dirs_in_chroot.insert(Cow::Owned(logical_path), (Cow::Owned(real_path), false));
     } else {
       fs::hard_link(
         &real_path,
-        chroot_root_dir.join(real_path.strip_prefix("/").unwrap()),
+        chroot_root_dir.join(logical_path.strip_prefix("/").unwrap()),
       )?;
     }
   }
 
   for out in drv.outputs.values() {
-    if let Ok(x) = store.to_real_path(&out.path) {
-      dirs_in_chroot.remove(x.as_path());
-    }
+    dirs_in_chroot.remove(Path::new(&store.print_store_path(&out.path)));
   }
 
   // stdout pipes for the builder process
@@ -267,8 +299,9 @@ pub(super) fn exec_builder<S: Store>(
   progress.set_prefix(&drv.name);
   progress.enable_steady_tick(1000);
 
-  let p2 = progress.clone();
-  scope.spawn(move |_| Logger::new(build_log_path, pipe_read, p2)?.run());
+  let logger = Logger::new(build_log_path, pipe_read, progress.clone())?;
+  let last_output = logger.last_output();
+  scope.spawn(move |_| logger.run());
 
   messages.push(Message::SpawnedProcess(pid as _));
 
@@ -291,7 +324,17 @@ pub(super) fn exec_builder<S: Store>(
   // signal the builder that it can go ahead
   user_ns_send.send(())?;
 
-  match waitpid(Some(unistd::Pid::from_raw(pid)), None)? {
+  // a limit of 0 means there is none
+  let status = wait_for_builder(
+    pid,
+    &store.print_store_path(path),
+    settings.timeout.filter(|t| !t.is_zero()),
+    settings.max_silent_time.filter(|t| !t.is_zero()),
+    &last_output,
+    build_user.as_ref(),
+  )?;
+
+  match status {
     WaitStatus::Exited(_, s) => {
       if s > 0 {
         bail!(
@@ -304,6 +347,21 @@ pub(super) fn exec_builder<S: Store>(
     s => bail!("unexpected wait status from child: {:?}", s),
   }
 
+  if check {
+    progress.set_message("checking outputs");
+    check_outputs(
+      store,
+      path,
+      drv,
+      &chroot_root_dir,
+      &builder_tmp,
+      build_user.as_ref(),
+    )?;
+    succeeded.set(true);
+    progress.finish_and_clear();
+    return Ok(Some(FinishedChild(pid as _)));
+  }
+
   progress.set_message("registering outputs");
 
   // Register the build outputs.
@@ -312,8 +370,9 @@ pub(super) fn exec_builder<S: Store>(
   for out in drv.outputs.values() {
     referenceable_paths.insert(out.path.clone());
 
-    let real_path = store.print_store_path(&out.path);
-    let chroot_path = chroot_root_dir.join(Path::new(&real_path).strip_prefix("/").unwrap());
+    let logical_path = store.print_store_path(&out.path);
+    let real_path = store.to_real_path(&out.path)?;
+    let chroot_path = chroot_root_dir.join(Path::new(&logical_path).strip_prefix("/").unwrap());
     if chroot_path.exists() {
       rm_rf(&real_path)?;
       fs::rename(chroot_path, real_path)?;
@@ -321,16 +380,17 @@ pub(super) fn exec_builder<S: Store>(
   }
 
   let mut pathinfos = vec![];
+  let mut hash_mismatch = None;
 
   for output in drv.outputs.values() {
-    let out_path = store.print_store_path(&output.path);
+    let out_path = store.to_real_path(&output.path)?;
 
     canonicalise_path_metadata(&out_path, None)?;
 
     let mut path_hash = crate::hash::Sink::new(HashType::SHA256);
     let mut scanner = crate::archive::RefsScanner::new(referenceable_paths.iter().cloned());
 
-    debug!("dumping {}", out_path);
+    debug!("dumping {}", out_path.display());
 
     crate::archive::dump_path(
       &out_path,
@@ -338,7 +398,7 @@ pub(super) fn exec_builder<S: Store>(
       &PathFilter::none(),
     )?;
 
-    let (path_hash, _) = path_hash.finish();
+    let (path_hash, nar_size) = path_hash.finish();
     let found_refs = scanner.finish();
 
     debug!("calculated hash"; "path" => %output.path, "hash" => path_hash.encode(Encoding::Base32));
@@ -346,16 +406,187 @@ pub(super) fn exec_builder<S: Store>(
 
     let mut valid_path = ValidPathInfo::new(output.path.clone(), path_hash);
     valid_path.references = found_refs;
+    valid_path.nar_size = Some(nar_size as u64);
+    valid_path.deriver = Some(path.clone());
+    if let Some(h) = &output.hash {
+      let actual_hash = fixed_output_hash(&out_path, h, path_hash)?;
+      valid_path.content_addressed = Some(fixed_output_ca(h.recursive, &actual_hash));
+
+      if actual_hash != h.hash {
+        hash_mismatch = Some(keep_mismatched_output(
+          store,
+          path,
+          &out_path,
+          h,
+          &actual_hash,
+          valid_path,
+        )?);
+        continue;
+      }
+    }
+    store.sign_path_info(&mut valid_path)?;
 
     pathinfos.push(valid_path);
   }
 
   store.register_valid_paths(pathinfos)?;
 
+  if let Some(e) = hash_mismatch {
+    return Err(e);
+  }
+
+  succeeded.set(true);
+
   progress.finish_and_clear();
   Ok(Some(FinishedChild(pid as _)))
 }
 
+/// Compare the outputs of a repeated build, still in the chroot, with the
+/// registered ones.
+fn check_outputs<S: Store>(
+  store: &S,
+  path: &StorePath,
+  drv: &Derivation,
+  chroot_root_dir: &Path,
+  builder_tmp: &Path,
+  build_user: Option<&UserLock>,
+) -> Result<()> {
+  for output in drv.outputs.values() {
+    let logical_path = store.print_store_path(&output.path);
+    let info = store
+      .get_path_info(&output.path)?
+      .ok_or_else(|| anyhow!("path {} is not valid", logical_path))?;
+    let new_path = chroot_root_dir.join(Path::new(&logical_path).strip_prefix("/").unwrap());
+    ensure!(
+      new_path.exists(),
+      "builder for {} failed to produce output path {}",
+      store.print_store_path(path),
+      logical_path
+    );
+
+    canonicalise_path_metadata(&new_path, None)?;
+    let (nar_hash, _) =
+      crate::archive::hash_path(&new_path, info.nar_hash().type_(), &PathFilter::none())?;
+    if nar_hash == *info.nar_hash() {
+      continue;
+    }
+
+    // keep the differing output next to the original one
+    let real_path = store.to_real_path(&output.path)?;
+    let mut check_path = real_path.clone().into_os_string();
+    check_path.push(".check");
+    let check_path = PathBuf::from(check_path);
+    rm_rf(&check_path)?;
+    fs::rename(&new_path, &check_path)?;
+
+    if settings().run_diff_hook {
+      if let Some(hook) = &settings().diff_hook {
+        let drv_path = store.print_store_path(path);
+        run_diff_hook(
+          hook,
+          &real_path,
+          &check_path,
+          &drv_path,
+          builder_tmp,
+          build_user,
+        );
+      }
+    }
+
+    let mut message = format!(
+      "derivation {} may not be deterministic: output {} differs",
+      store.print_store_path(path),
+      logical_path
+    );
+    if settings().keep_failed {
+      message.push_str(&format!(" from {}", check_path.display()));
+    } else {
+      rm_rf(&check_path)?;
+    }
+    if settings().enforce_determinism {
+      bail!(message);
+    }
+    warn!("{}", message);
+  }
+  Ok(())
+}
+
+/// Wait for the builder `pid` of `drv_path` to exit, killing it once it has
+/// run for longer than `timeout` or been silent for longer than
+/// `max_silent_time`.
+fn wait_for_builder(
+  pid: libc::pid_t,
+  drv_path: &str,
+  timeout: Option<Duration>,
+  max_silent_time: Option<Duration>,
+  last_output: &Mutex<Instant>,
+  build_user: Option<&UserLock>,
+) -> Result<WaitStatus> {
+  let started = Instant::now();
+  loop {
+    match waitpid(Some(unistd::Pid::from_raw(pid)), Some(WaitPidFlag::WNOHANG))? {
+      WaitStatus::StillAlive => {}
+      s => return Ok(s),
+    }
+
+    let timed_out = if let Some(t) = timeout.filter(|t| started.elapsed() > *t) {
+      Some(TimedOut::Timeout(drv_path.into(), t.as_secs()))
+    } else if let Some(t) = max_silent_time.filter(|t| last_output.lock().elapsed() > *t) {
+      Some(TimedOut::Silent(drv_path.into(), t.as_secs()))
+    } else {
+      None
+    };
+
+    if let Some(e) = timed_out {
+      // the builder is the init process of its PID namespace, so everything
+      // else in the namespace dies with it
+      Pid::new(pid).kill()?;
+      if let Some(user) = build_user {
+        user.kill()?;
+      }
+      bail!(e);
+    }
+
+    thread::sleep(Duration::from_millis(100));
+  }
+}
+
+/// Show the user how two builds of `drv_path` differ. Failing to do so isn't
+/// an error. The hook looks at untrusted build output, so it runs as the
+/// build user if there is one.
+fn run_diff_hook(
+  hook: &Path,
+  old: &Path,
+  new: &Path,
+  drv_path: &str,
+  builder_tmp: &Path,
+  build_user: Option<&UserLock>,
+) {
+  let mut cmd = Command::new(hook);
+  cmd
+    .arg(old)
+    .arg(new)
+    .arg(drv_path)
+    .arg(builder_tmp)
+    .current_dir(builder_tmp)
+    .stdin(Stdio::null());
+  if let Some(user) = build_user {
+    cmd.uid(user.uid.as_raw()).gid(user.gid.as_raw());
+  }
+  let output = cmd.output();
+  match output {
+    Ok(output) => {
+      for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("{}", line);
+      }
+      if !output.status.success() {
+        warn!("diff hook `{}' failed: {}", hook.display(), output.status);
+      }
+    }
+    Err(e) => warn!("unable to run diff hook `{}': {}", hook.display(), e),
+  }
+}
+
 fn mk_command<S: Store>(store: &S, drv: &Derivation) -> Result<Command> {
   let mut rewrites = HashMap::new();
 
@@ -639,3 +870,56 @@ fn common_child_init() -> Result<()> {
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use unix::sys::signal::kill;
+
+  fn spawn_sleep() -> Result<libc::pid_t> {
+    Ok(Command::new("sleep").arg("10").spawn()?.id() as _)
+  }
+
+  #[test]
+  fn test_timeout() -> Result<()> {
+    let pid = spawn_sleep()?;
+    let e = wait_for_builder(
+      pid,
+      "/nix/store/foo.drv",
+      Some(Duration::from_millis(200)),
+      None,
+      &Mutex::new(Instant::now()),
+      None,
+    )
+    .unwrap_err();
+    assert!(
+      matches!(e.downcast_ref::<TimedOut>(), Some(TimedOut::Timeout(..))),
+      "{:#}",
+      e
+    );
+    // killed and reaped
+    assert!(kill(unistd::Pid::from_raw(pid), None).is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_max_silent_time() -> Result<()> {
+    let pid = spawn_sleep()?;
+    let e = wait_for_builder(
+      pid,
+      "/nix/store/foo.drv",
+      Some(Duration::from_secs(10)),
+      Some(Duration::from_millis(200)),
+      &Mutex::new(Instant::now()),
+      None,
+    )
+    .unwrap_err();
+    assert!(
+      matches!(e.downcast_ref::<TimedOut>(), Some(TimedOut::Silent(..))),
+      "{:#}",
+      e
+    );
+    assert!(kill(unistd::Pid::from_raw(pid), None).is_err());
+    Ok(())
+  }
+}
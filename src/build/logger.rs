@@ -1,11 +1,14 @@
 use crate::util::*;
 use indicatif::ProgressBar;
+use parking_lot::Mutex;
 use serde::Deserialize;
 use std::{
   fs::File,
   io::{BufWriter, Write},
   os::unix::prelude::*,
   path::Path,
+  sync::Arc,
+  time::Instant,
 };
 
 pub struct Logger {
@@ -14,6 +17,7 @@ pub struct Logger {
   progress: ProgressBar,
   current_line: String,
   phase: Option<String>,
+  last_output: Arc<Mutex<Instant>>,
 }
 
 impl Logger {
@@ -24,9 +28,15 @@ impl Logger {
       progress,
       current_line: String::new(),
       phase: None,
+      last_output: Arc::new(Mutex::new(Instant::now())),
     })
   }
 
+  /// When the builder last wrote anything, for enforcing `max-silent-time`.
+  pub fn last_output(&self) -> Arc<Mutex<Instant>> {
+    Arc::clone(&self.last_output)
+  }
+
   fn handle_line(&mut self) {
     #[derive(Deserialize)]
     #[serde(rename_all = "camelCase", tag = "action")]
@@ -71,6 +81,7 @@ impl Logger {
       if len == 0 {
         break;
       }
+      *self.last_output.lock() = Instant::now();
       self.file.write_all(&data[..len])?;
 
       // the log line display logic is moderately expensive
@@ -1,20 +1,32 @@
-use self::{dependency_queue::DependencyQueue, logger::Logger, queue::Queue};
-use crate::{archive::PathFilter, prelude::*};
+use self::{
+  dependency_queue::DependencyQueue, logger::Logger, queue::Queue, substitute::Substituter,
+};
+use crate::{
+  archive::PathFilter,
+  derivation::FixedOutputHash,
+  prelude::*,
+  store::{FileIngestionMethod, RepairFlag},
+};
 use crossbeam::thread::Scope;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use once_cell::unsync::OnceCell;
 use std::{
-  collections::{BTreeSet, HashMap, HashSet},
+  collections::{BTreeMap, BTreeSet, HashMap, HashSet},
   io::BufReader,
   os::unix::prelude::*,
   process::*,
   sync::Arc,
 };
 use tee_readwrite::TeeWriter;
-use unix::fcntl::OFlag;
+use unix::{
+  fcntl::OFlag,
+  unistd::{Gid, Uid},
+};
 
 mod dependency_queue;
 mod logger;
 mod queue;
+mod substitute;
 
 #[cfg(target_os = "linux")]
 #[path = "linux.rs"]
@@ -24,6 +36,16 @@ mod sys;
 #[path = "macos.rs"]
 mod sys;
 
+/// A builder that was killed for exceeding the `timeout` or
+/// `max-silent-time` settings.
+#[derive(thiserror::Error, Debug)]
+pub enum TimedOut {
+  #[error("building of {0} timed out after {1} seconds")]
+  Timeout(String, u64),
+  #[error("{0} killed after {1} seconds of silence")]
+  Silent(String, u64),
+}
+
 #[derive(Debug)]
 struct Build {
   child: Child,
@@ -42,18 +64,45 @@ enum Message {
     // the pid of a process that should be removed from self.active_pids.
     result: Result<Option<FinishedChild>>,
   },
+  // Substitution failed and we're allowed to fall back to building.
+  SubstitutionFailed {
+    job_id: usize,
+    derivation: Derivation,
+  },
   SpawnedProcess(u32),
 }
 
+/// Why a derivation wasn't built, in keep-going mode.
+#[derive(Debug)]
+enum Failure {
+  Build(anyhow::Error),
+  /// The given input derivation couldn't be built.
+  DependencyFailed(StorePath),
+}
+
 #[derive(Debug)]
 pub struct Worker<'a, S: Store = crate::store::LocalStore> {
   queue: DependencyQueue<StorePath, String, Derivation>,
   pending: Vec<(StorePath, Derivation)>,
   active: HashMap<usize, StorePath>,
+  // every derivation that has been added to the queue
+  known: HashSet<StorePath>,
+  finished: HashSet<StorePath>,
+  // derivations whose outputs we're going to try to substitute
+  substitutable: HashSet<StorePath>,
+  // derivations to build even though their outputs are valid
+  repair: HashSet<StorePath>,
+  // derivations to rebuild to compare against their valid outputs
+  check: HashSet<StorePath>,
+  // derivations that weren't built, when we keep going after failures
+  failed: BTreeMap<StorePath, Failure>,
+  substituters: OnceCell<Arc<Vec<Substituter>>>,
   messages: Arc<Queue<Message>>,
   next_id: usize,
   active_pids: HashSet<u32>,
   progress: Arc<MultiProgress>,
+  // the user who asked for the builds, if not the one running them
+  requester: Option<(Uid, Gid)>,
   store: &'a S,
 }
 
@@ -64,29 +113,127 @@ impl<'a, S: Store> Worker<'a, S> {
       pending: Default::default(),
       queue: Default::default(),
       active: Default::default(),
+      known: Default::default(),
+      finished: Default::default(),
+      substitutable: Default::default(),
+      repair: Default::default(),
+      check: Default::default(),
+      failed: Default::default(),
+      substituters: OnceCell::new(),
       messages: Arc::new(Queue::new(100)),
       next_id: 0,
       active_pids: HashSet::new(),
       progress: Arc::new(MultiProgress::new()),
+      requester: None,
     }
   }
 
-  // FIXME: This method produces a dependency queue of X objects (i.e. the entire
-  // dependency tree) even if everything has already been built.
+  /// Build on behalf of `requester`, who gets the build directories of
+  /// failed builds when `keep-failed` is set. Defaults to the current user.
+  pub fn set_requester(&mut self, requester: Option<(Uid, Gid)>) {
+    self.requester = requester;
+  }
+
   pub fn add_needed(&mut self, path: &StorePath) -> Result<()> {
+    if !self.known.insert(path.clone()) {
+      return Ok(());
+    }
+
     let drv = Derivation::get(&*self.store, path)?;
-    let deps_iter = drv
+    // keep the derivation and its outputs from being garbage collected while
+    // the build is queued
+    self.store.add_temp_root(path)?;
+    for out in drv.out_paths() {
+      self.store.add_temp_root(out)?;
+    }
+
+    let mut missing = vec![];
+    for out in drv.out_paths() {
+      if !self.store.is_valid_path(out)? {
+        missing.push(out.clone());
+      }
+    }
+
+    if self.check.contains(path) {
+      ensure!(
+        missing.is_empty(),
+        "some outputs of {} are not valid, so checking is not possible",
+        self.store.print_store_path(path)
+      );
+    }
+
+    // inputs are only needed if we actually have to build something
+    if self.repair.contains(path) || self.check.contains(path) {
+      self.enqueue_with_inputs(path, drv)?;
+    } else if missing.is_empty() {
+      self.queue.enqueue(path.clone(), drv, std::iter::empty());
+    } else if self.can_substitute(&missing)? {
+      self.substitutable.insert(path.clone());
+      self.queue.enqueue(path.clone(), drv, std::iter::empty());
+    } else {
+      self.enqueue_with_inputs(path, drv)?;
+    }
+
+    Ok(())
+  }
+
+  /// Rebuild `path`, replacing its outputs even if they're already valid.
+  pub fn add_repair(&mut self, path: &StorePath) -> Result<()> {
+    self.repair.insert(path.clone());
+    self.add_needed(path)
+  }
+
+  /// Rebuild `path`, whose outputs must be valid, and compare the result with
+  /// the existing outputs instead of registering it.
+  pub fn add_check(&mut self, path: &StorePath) -> Result<()> {
+    self.check.insert(path.clone());
+    self.add_needed(path)
+  }
+
+  fn enqueue_with_inputs(&mut self, path: &StorePath, drv: Derivation) -> Result<()> {
+    for input in drv.input_derivations.keys() {
+      self.add_needed(input)?;
+    }
+    // only possible when falling back from substitution in keep-going mode
+    if let Some(input) = drv
+      .input_derivations
+      .keys()
+      .find(|p| self.failed.contains_key(*p))
+    {
+      self
+        .failed
+        .insert(path.clone(), Failure::DependencyFailed(input.clone()));
+      return Ok(());
+    }
+    let deps = drv
       .input_derivations
       .iter()
+      .filter(|(path, _)| !self.finished.contains(*path))
       .flat_map(|(path, outputs)| outputs.iter().map(move |o| (path.clone(), o.clone())))
       .collect::<Vec<(StorePath, String)>>();
-    for path in drv.input_derivations.keys() {
-      if !self.queue.dep_map.contains_key(path) {
-        self.add_needed(path)?;
+    self.queue.enqueue(path.clone(), drv, deps);
+    Ok(())
+  }
+
+  fn substituters(&self) -> Arc<Vec<Substituter>> {
+    Arc::clone(
+      self
+        .substituters
+        .get_or_init(|| Arc::new(substitute::default_substituters())),
+    )
+  }
+
+  fn can_substitute(&self, paths: &[StorePath]) -> Result<bool> {
+    let substituters = self.substituters();
+    if substituters.is_empty() {
+      return Ok(false);
+    }
+    for path in paths {
+      if substitute::query(&substituters, path)?.is_none() {
+        return Ok(false);
       }
     }
-    self.queue.enqueue(path.clone(), drv, deps_iter);
-    Ok(())
+    Ok(true)
   }
 
   fn spawn_if_possible(&mut self, scope: &Scope<'a>) -> Result<()> {
@@ -148,6 +295,9 @@ impl<'a, S: Store> Worker<'a, S> {
 
     if let Some(e) = error {
       return Err(e);
+    } else if !self.failed.is_empty() {
+      all_jobs.abandon();
+      return Err(self.failure_summary());
     } else if self.queue.is_empty() && self.pending.is_empty() {
       all_jobs.finish_and_clear()
     } else {
@@ -157,6 +307,30 @@ impl<'a, S: Store> Worker<'a, S> {
     Ok(())
   }
 
+  fn failure_summary(&self) -> anyhow::Error {
+    let mut builds = vec![];
+    for (path, failure) in &self.failed {
+      let path = self.store.print_store_path(path);
+      match failure {
+        Failure::Build(e) => {
+          error!("build of {} failed: {:#}", path, e);
+          builds.push(path);
+        }
+        Failure::DependencyFailed(input) => error!(
+          "cannot build {}: dependency {} failed",
+          path,
+          self.store.print_store_path(input)
+        ),
+      }
+    }
+    anyhow!(
+      "{} derivations failed to build ({} because of failed dependencies): {}",
+      self.failed.len(),
+      self.failed.len() - builds.len(),
+      builds.join(", ")
+    )
+  }
+
   fn handle_error(
     &self,
     some_error: &mut Option<anyhow::Error>,
@@ -182,23 +356,51 @@ impl<'a, S: Store> Worker<'a, S> {
         result,
       } => {
         let thingy = self.active.remove(&job_id).unwrap();
-        for out in &outputs {
-          self.queue.finish(&thingy, out);
-        }
         match result {
           Ok(x) => {
             debug!("build finished"; "path" => %thingy, "outputs" => ?outputs);
+            for out in &outputs {
+              self.queue.finish(&thingy, out);
+            }
             all_jobs.inc(1);
+            self.finished.insert(thingy);
             if let Some(pid) = x {
               self.active_pids.remove(&*pid);
             }
           }
           Err(e) => {
             let _ = self.try_show_log(&thingy);
-            bail!(e)
+            if !settings().keep_going {
+              bail!(e)
+            }
+            warn!("{:#}", e);
+            all_jobs.inc(1);
+            for dependent in self.queue.fail(&thingy) {
+              all_jobs.inc(1);
+              self
+                .failed
+                .insert(dependent, Failure::DependencyFailed(thingy.clone()));
+            }
+            self.failed.insert(thingy, Failure::Build(e));
           }
         }
       }
+      Message::SubstitutionFailed { job_id, derivation } => {
+        let path = self.active.remove(&job_id).unwrap();
+        debug!("substitution failed, building from source"; "path" => %path);
+        self.substitutable.remove(&path);
+        let queued = self.queue.len();
+        self.enqueue_with_inputs(&path, derivation)?;
+        let added = (self.queue.len() - queued) as u64;
+        // `path` itself was already counted
+        if self.failed.contains_key(&path) {
+          all_jobs.inc(1);
+          all_jobs.inc_length(added);
+        } else {
+          all_jobs.inc_length(added - 1);
+        }
+        self.queue.queue_finished();
+      }
       Message::SpawnedProcess(pid) => {
         assert!(self.active_pids.insert(pid));
       }
@@ -260,11 +462,19 @@ impl<'a, S: Store> Worker<'a, S> {
     let messages = Arc::clone(&self.messages);
     let pog = Arc::clone(&self.progress);
     let store = self.store;
+    let repair = self.repair.contains(&path);
+    let check = self.check.contains(&path);
+    let requester = self.requester;
+    let substituters = if self.substitutable.contains(&path) {
+      Some(self.substituters())
+    } else {
+      None
+    };
 
     let doit = move |scope: &Scope<'_>| {
       let mut result = Ok(None);
 
-      let mut needs_build = false;
+      let mut needs_build = repair || check;
       for out in drv.outputs.values() {
         if !store.is_valid_path(&out.path).unwrap_or(false) {
           needs_build = true;
@@ -281,10 +491,58 @@ impl<'a, S: Store> Worker<'a, S> {
         return;
       }
 
-      result = if drv.is_builtin() {
-        exec_builtin(store, &messages, &drv, &pog).map(|_| None)
+      if let Some(substituters) = substituters {
+        let missing = drv
+          .out_paths()
+          .filter(|p| !store.is_valid_path(p).unwrap_or(false))
+          .cloned()
+          .collect::<Vec<_>>();
+        match substitute::substitute_paths(store, &substituters, &missing, &pog) {
+          Ok(()) => {}
+          Err(e) if settings().try_fallback => {
+            warn!("{:#}", e);
+            messages.push(Message::SubstitutionFailed {
+              job_id: id,
+              derivation: drv,
+            });
+            return;
+          }
+          Err(e) => {
+            result = Err(e.context(format!(
+              "some substitutes for the outputs of derivation {} failed (usually happens due \
+               to networking issues); try `--fallback' to build derivation from source",
+              store.print_store_path(&path)
+            )));
+          }
+        }
+        messages.push(Message::Finish {
+          job_id: id,
+          outputs: drv.outputs.keys().cloned().collect(),
+          result,
+        });
+        return;
+      }
+
+      result = if drv.is_builtin() && check {
+        Err(anyhow!(
+          "checking builtin derivation {} is not supported",
+          store.print_store_path(&path)
+        ))
+      } else if drv.is_builtin() {
+        exec_builtin(store, &messages, &path, &drv, &pog).map(|_| None)
       } else {
-        self::sys::exec_builder(store, &messages, scope, &path, &drv, &pog)
+        self::sys::exec_builder(store, &messages, scope, &path, &drv, &pog, check, requester)
+          .and_then(|x| {
+            // a fresh build may be repeated to check that it's deterministic
+            if !check {
+              for _ in 0..settings().build_repeat {
+                self::sys::exec_builder(
+                  store, &messages, scope, &path, &drv, &pog, true, requester,
+                )?;
+              }
+            }
+            Ok(x)
+          })
       };
 
       messages.push(Message::Finish {
@@ -300,9 +558,55 @@ impl<'a, S: Store> Worker<'a, S> {
   }
 }
 
+/// Restore the contents of the valid path `path`, by substituting it if
+/// possible and otherwise by rebuilding its deriver.
+pub fn repair_path<S: Store>(store: &S, path: &StorePath) -> Result<()> {
+  let substituters = substitute::default_substituters();
+  if let Some((sub, info)) = substitute::query(&substituters, path)? {
+    return substitute::fetch_path(store, sub, &info, RepairFlag::Repair, &MultiProgress::new());
+  }
+
+  let info = store
+    .get_path_info(path)?
+    .ok_or_else(|| anyhow!("path {} is not valid", store.print_store_path(path)))?;
+  match info.deriver() {
+    Some(deriver) if store.is_valid_path(deriver)? => {
+      let mut worker = Worker::with_store(store);
+      worker.add_repair(deriver)?;
+      worker.build()
+    }
+    _ => bail!(
+      "path {} has no substitutes and no known deriver",
+      store.print_store_path(path)
+    ),
+  }
+}
+
+/// Make `path` valid by substituting it, if it isn't already.
+pub fn ensure_path<S: Store>(store: &S, path: &StorePath) -> Result<()> {
+  if store.is_valid_path(path)? {
+    return Ok(());
+  }
+  let substituters = substitute::default_substituters();
+  match substitute::query(&substituters, path)? {
+    Some((sub, info)) => substitute::fetch_path(
+      store,
+      sub,
+      &info,
+      RepairFlag::NoRepair,
+      &MultiProgress::new(),
+    ),
+    None => bail!(
+      "path {} does not exist and cannot be created",
+      store.print_store_path(path)
+    ),
+  }
+}
+
 fn exec_builtin<S: Store>(
   store: &S,
   _messages: &Arc<Queue<Message>>,
+  path: &StorePath,
   drv: &Derivation,
   progress: &Arc<MultiProgress>,
 ) -> Result<()> {
@@ -316,12 +620,31 @@ fn exec_builtin<S: Store>(
   progress.set_prefix(&drv.name);
 
   if drv.builder.to_str() == Some("builtin:fetchurl") {
-    let fetched_hash = crate::fetch::fetchurl(&drv, &progress)?;
-
-    store.register_valid_path(ValidPathInfo::new(
-      drv.outputs["out"].path.clone(),
-      fetched_hash,
-    ))?;
+    let dest = store.to_real_path(&drv.outputs["out"].path)?;
+    crate::fetch::fetchurl(&drv, &dest, &progress)?;
+
+    let (nar_hash, nar_size) =
+      crate::archive::hash_path(&dest, HashType::SHA256, &PathFilter::none())?;
+    let mut info = ValidPathInfo::new(drv.outputs["out"].path.clone(), nar_hash);
+    info.nar_size = Some(nar_size as u64);
+    info.deriver = Some(path.clone());
+    if let Some(h) = &drv.outputs["out"].hash {
+      let actual_hash = fixed_output_hash(&dest, h, nar_hash)?;
+      info.content_addressed = Some(crate::path_info::fixed_output_ca(h.recursive, &actual_hash));
+      if actual_hash != h.hash {
+        progress.finish_and_clear();
+        return Err(keep_mismatched_output(
+          store,
+          path,
+          &dest,
+          h,
+          &actual_hash,
+          info,
+        )?);
+      }
+    }
+    store.sign_path_info(&mut info)?;
+    store.register_valid_path(info)?;
 
     progress.finish_and_clear();
 
@@ -330,3 +653,65 @@ fn exec_builtin<S: Store>(
     bail!("unknown builtin: {}", drv.builder.display())
   }
 }
+
+/// The hash of the output at `path` of a fixed-output derivation, computed the
+/// way `expected` was: over the file itself, or over its NAR if recursive.
+/// `nar_hash` is the SHA-256 hash of the NAR, which saves dumping it again in
+/// the common case.
+fn fixed_output_hash(path: &Path, expected: &FixedOutputHash, nar_hash: Hash) -> Result<Hash> {
+  Ok(if !expected.recursive {
+    Hash::hash_file(path, expected.hash.type_())?.0
+  } else if expected.hash.type_() == HashType::SHA256 {
+    nar_hash
+  } else {
+    crate::archive::hash_path(path, expected.hash.type_(), &PathFilter::none())?.0
+  })
+}
+
+/// Deal with the output at `out_path` of the fixed-output derivation `path`
+/// hashing to `actual_hash` rather than what was `expected`. The output is
+/// kept where a derivation with the right hash would put it, so that fixing
+/// the hash doesn't mean downloading it again. Returns the error to report.
+fn keep_mismatched_output<S: Store>(
+  store: &S,
+  path: &StorePath,
+  out_path: &Path,
+  expected: &FixedOutputHash,
+  actual_hash: &Hash,
+  mut info: ValidPathInfo,
+) -> Result<anyhow::Error> {
+  let ingest_method = if expected.recursive {
+    FileIngestionMethod::Recursive
+  } else {
+    FileIngestionMethod::Flat
+  };
+  let side_path = store.make_fixed_output_path(
+    ingest_method,
+    actual_hash,
+    &info.store_path.name,
+    std::iter::empty(),
+    false,
+  )?;
+
+  if store.is_valid_path(&side_path)? {
+    rm_rf(out_path)?;
+  } else {
+    let real_side_path = store.to_real_path(&side_path)?;
+    rm_rf(&real_side_path)?;
+    fs::rename(out_path, &real_side_path)?;
+    info.store_path = side_path.clone();
+    store.sign_path_info(&mut info)?;
+    store.register_valid_path(info)?;
+  }
+
+  Ok(anyhow!(
+    "hash mismatch in fixed-output derivation {}:\n  specified: {} ({})\n  got:       {} \
+     ({})\nthe output has been kept at {}",
+    store.print_store_path(path),
+    expected.hash.encode_with_type(Encoding::SRI),
+    expected.hash.encode_with_type(Encoding::Base32),
+    actual_hash.encode_with_type(Encoding::SRI),
+    actual_hash.encode_with_type(Encoding::Base32),
+    store.print_store_path(&side_path)
+  ))
+}
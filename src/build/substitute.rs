@@ -0,0 +1,132 @@
+//! Fetching the outputs of derivations from binary caches instead of building
+//! them.
+
+use crate::{
+  nar_info::NarInfo,
+  prelude::*,
+  store::{binary_cache, topo_sort, BinaryCacheStore, CheckSigsFlag, RepairFlag},
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+pub type Substituter = BinaryCacheStore<Box<dyn binary_cache::BinaryCache>>;
+
+/// Opens every configured substituter, most preferred first. Substituters that
+/// can't be opened are skipped.
+pub fn default_substituters() -> Vec<Substituter> {
+  let settings = settings();
+  if !settings.use_substitutes {
+    return vec![];
+  }
+
+  let mut seen = HashSet::new();
+  let mut substituters = settings
+    .substituters
+    .iter()
+    .chain(&settings.extra_substituters)
+    .filter(|uri| seen.insert(uri.as_str()))
+    .filter_map(|uri| match binary_cache::open(uri) {
+      Ok(s) => Some(s),
+      Err(e) => {
+        warn!("ignoring substituter `{}': {:#}", uri, e);
+        None
+      }
+    })
+    .collect::<Vec<_>>();
+  substituters.sort_by_key(|s| s.priority());
+  substituters
+}
+
+/// Asks each substituter in turn whether it can provide `path`.
+pub fn query<'s>(
+  substituters: &'s [Substituter],
+  path: &StorePath,
+) -> Result<Option<(&'s Substituter, NarInfo)>> {
+  for sub in substituters {
+    match sub.query_nar_info(path) {
+      Ok(Some(info)) => return Ok(Some((sub, info))),
+      Ok(None) => {}
+      Err(e) if settings().try_fallback => {
+        warn!("unable to query substituter `{}': {:#}", sub.uri(), e)
+      }
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(None)
+}
+
+/// Substitutes `paths` and every path in their closure that isn't valid yet,
+/// references first.
+pub fn substitute_paths<S: Store>(
+  store: &S,
+  substituters: &[Substituter],
+  paths: &[StorePath],
+  progress: &MultiProgress,
+) -> Result<()> {
+  let mut missing = BTreeMap::new();
+  let mut todo = paths.to_vec();
+
+  while let Some(path) = todo.pop() {
+    if missing.contains_key(&path) || store.is_valid_path(&path)? {
+      continue;
+    }
+    let (sub, info) = query(substituters, &path)?.ok_or_else(|| {
+      anyhow!(
+        "path {} is required, but there is no substituter that can provide it",
+        store.print_store_path(&path)
+      )
+    })?;
+    todo.extend(info.references().iter().filter(|r| *r != &path).cloned());
+    missing.insert(path, (sub, info));
+  }
+
+  let paths = missing.keys().cloned().collect::<BTreeSet<_>>();
+  let sorted = topo_sort(&paths, |p| Ok(missing[p].1.references().clone()))?;
+
+  for path in sorted.iter().rev() {
+    let (sub, info) = &missing[path];
+    fetch_path(store, sub, info, RepairFlag::NoRepair, progress)?;
+  }
+
+  Ok(())
+}
+
+/// Fetch a single path whose references are already valid. If `repair` is
+/// set, the path is replaced even if it's already valid.
+pub fn fetch_path<S: Store>(
+  store: &S,
+  sub: &Substituter,
+  info: &NarInfo,
+  repair: RepairFlag,
+  progress: &MultiProgress,
+) -> Result<()> {
+  let path = &info.path_info.store_path;
+
+  debug!("substituting path"; "path" => %path, "from" => sub.uri());
+
+  let bar = progress.insert(
+    0,
+    ProgressBar::new(info.nar_size().unwrap_or(0)).with_style(
+      ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {prefix:.blue} {bar:40} {bytes}/{total_bytes}"),
+    ),
+  );
+  bar.set_prefix(&path.name);
+
+  let result: Result<()> = try {
+    store.add_to_store_from_source(
+      info.path_info.clone(),
+      bar.wrap_read(sub.nar_reader(info)?),
+      repair,
+      CheckSigsFlag::CheckSigs,
+    )?;
+  };
+  bar.finish_and_clear();
+  result.with_context(|| {
+    format!(
+      "while substituting {} from `{}'",
+      store.print_store_path(path),
+      sub.uri()
+    )
+  })
+}
@@ -0,0 +1,198 @@
+//! The worker protocol spoken between Nix clients and `nix-daemon`.
+//!
+//! Everything on the wire is either a 64-bit little-endian number or a
+//! length-prefixed string padded to a multiple of 8 bytes, the same encoding
+//! NARs use.
+
+use crate::{archive, prelude::*};
+use std::{collections::BTreeSet, convert::TryFrom, time::SystemTime};
+
+pub mod server;
+
+pub const WORKER_MAGIC_1: usize = 0x6e69_7863;
+pub const WORKER_MAGIC_2: usize = 0x6478_696f;
+
+pub const PROTOCOL_VERSION: usize = 1 << 8 | 21;
+
+pub fn protocol_major(version: usize) -> usize {
+  version & 0xff00
+}
+
+pub fn protocol_minor(version: usize) -> usize {
+  version & 0x00ff
+}
+
+pub const STDERR_NEXT: usize = 0x6f6c_6d67;
+pub const STDERR_READ: usize = 0x6461_7461;
+pub const STDERR_WRITE: usize = 0x6461_7416;
+pub const STDERR_LAST: usize = 0x616c_7473;
+pub const STDERR_ERROR: usize = 0x6378_7470;
+pub const STDERR_START_ACTIVITY: usize = 0x5354_5254;
+pub const STDERR_STOP_ACTIVITY: usize = 0x5354_4f50;
+pub const STDERR_RESULT: usize = 0x5253_4c54;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+  IsValidPath = 1,
+  QueryReferrers = 6,
+  AddToStore = 7,
+  AddTextToStore = 8,
+  BuildPaths = 9,
+  EnsurePath = 10,
+  AddTempRoot = 11,
+  SyncWithGC = 13,
+  SetOptions = 19,
+  QueryDerivationOutputs = 22,
+  QueryAllValidPaths = 23,
+  QueryPathInfo = 26,
+  QueryValidPaths = 31,
+  QueryValidDerivers = 33,
+  OptimiseStore = 34,
+  VerifyStore = 35,
+  AddSignatures = 37,
+  NarFromPath = 38,
+  AddToStoreNar = 39,
+}
+
+impl TryFrom<usize> for Op {
+  type Error = anyhow::Error;
+
+  fn try_from(op: usize) -> Result<Self> {
+    Ok(match op {
+      1 => Self::IsValidPath,
+      6 => Self::QueryReferrers,
+      7 => Self::AddToStore,
+      8 => Self::AddTextToStore,
+      9 => Self::BuildPaths,
+      10 => Self::EnsurePath,
+      11 => Self::AddTempRoot,
+      13 => Self::SyncWithGC,
+      19 => Self::SetOptions,
+      22 => Self::QueryDerivationOutputs,
+      23 => Self::QueryAllValidPaths,
+      26 => Self::QueryPathInfo,
+      31 => Self::QueryValidPaths,
+      33 => Self::QueryValidDerivers,
+      34 => Self::OptimiseStore,
+      35 => Self::VerifyStore,
+      37 => Self::AddSignatures,
+      38 => Self::NarFromPath,
+      39 => Self::AddToStoreNar,
+      x => bail!("invalid operation {}", x),
+    })
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BuildMode {
+  Normal = 0,
+  Repair = 1,
+  Check = 2,
+}
+
+pub fn read_num<R: Read>(source: &mut R) -> Result<usize> {
+  archive::read_num(source)
+}
+
+pub fn read_bool<R: Read>(source: &mut R) -> Result<bool> {
+  Ok(read_num(source)? != 0)
+}
+
+pub fn read_string<R: Read>(source: &mut R) -> Result<String> {
+  Ok(String::from_utf8(archive::read_bytes(source)?)?)
+}
+
+pub fn read_strings<R: Read>(source: &mut R) -> Result<BTreeSet<String>> {
+  (0..read_num(source)?)
+    .map(|_| read_string(source))
+    .collect()
+}
+
+pub fn read_store_path<S: Store + ?Sized, R: Read>(store: &S, source: &mut R) -> Result<StorePath> {
+  store.parse_store_path(read_string(source)?)
+}
+
+pub fn read_store_paths<S: Store + ?Sized, R: Read>(
+  store: &S,
+  source: &mut R,
+) -> Result<BTreeSet<StorePath>> {
+  (0..read_num(source)?)
+    .map(|_| read_store_path(store, source))
+    .collect()
+}
+
+pub fn write_strings<W: Write, I: IntoIterator<Item = S>, S: AsRef<[u8]>>(
+  sink: &mut archive::Sink<W>,
+  strings: I,
+) -> io::Result<()> {
+  let strings = strings.into_iter().collect::<Vec<_>>();
+  sink.write_usize(strings.len())?;
+  for s in strings {
+    sink.write_tag(s)?;
+  }
+  Ok(())
+}
+
+pub fn write_store_paths<'a, S: Store + ?Sized, W: Write, I: IntoIterator<Item = &'a StorePath>>(
+  store: &S,
+  sink: &mut archive::Sink<W>,
+  paths: I,
+) -> io::Result<()> {
+  write_strings(sink, paths.into_iter().map(|p| store.print_store_path(p)))
+}
+
+/// Read the metadata of `path` in the order used by `wopQueryPathInfo` and
+/// `wopAddToStoreNar`.
+pub fn read_path_info<S: Store + ?Sized, R: Read>(
+  store: &S,
+  source: &mut R,
+  path: StorePath,
+) -> Result<ValidPathInfo> {
+  let deriver = read_string(source)?;
+  let nar_hash = Hash::decode_with_type(&read_string(source)?, HashType::SHA256, false)?;
+  let mut info = ValidPathInfo::new(path, nar_hash);
+  if !deriver.is_empty() {
+    info.deriver = Some(store.parse_store_path(deriver)?);
+  }
+  info.references = read_store_paths(store, source)?;
+  info.registration_time = SystemTime::UNIX_EPOCH + Duration::from_secs(read_num(source)? as u64);
+  info.nar_size = Some(read_num(source)? as u64).filter(|&n| n != 0);
+  info.ultimate = read_bool(source)?;
+  info.signatures = read_strings(source)?;
+  let content_addressed = read_string(source)?;
+  if !content_addressed.is_empty() {
+    info.content_addressed = Some(content_addressed);
+  }
+  Ok(info)
+}
+
+/// Write the metadata in `info` for a peer speaking minor version
+/// `peer_minor` of the protocol.
+pub fn write_path_info<S: Store + ?Sized, W: Write>(
+  store: &S,
+  sink: &mut archive::Sink<W>,
+  info: &ValidPathInfo,
+  peer_minor: usize,
+) -> io::Result<()> {
+  sink.write_tag(
+    info
+      .deriver
+      .as_ref()
+      .map_or_else(String::new, |d| store.print_store_path(d)),
+  )?;
+  sink.write_tag(info.nar_hash.encode(Encoding::Base16))?;
+  write_store_paths(store, sink, &info.references)?;
+  sink.write_usize(
+    info
+      .registration_time
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .map_or(0, |d| d.as_secs() as usize),
+  )?;
+  sink.write_usize(info.nar_size.unwrap_or(0) as usize)?;
+  if peer_minor >= 16 {
+    sink.write_usize(info.ultimate as usize)?;
+    write_strings(sink, &info.signatures)?;
+    sink.write_tag(info.content_addressed.as_deref().unwrap_or(""))?;
+  }
+  Ok(())
+}
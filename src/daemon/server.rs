@@ -0,0 +1,599 @@
+//! The daemon side of the worker protocol. Each connection is served by its
+//! own process, so log output is simply redirected to the client for the
+//! lifetime of the process.
+
+use super::*;
+use crate::{
+  archive::PathFilter,
+  build::Worker,
+  store::{CheckSigsFlag, FileIngestionMethod, LocalStore, RepairFlag},
+};
+use slog::{Drain, OwnedKVList, Record};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct Tunnel<W: Write> {
+  // whether the client is currently expecting log messages
+  can_send: Mutex<bool>,
+  pending: Mutex<Vec<String>>,
+  sink: Mutex<archive::Sink<W>>,
+}
+
+/// Sends log messages to the client as `STDERR_NEXT` frames. Messages logged
+/// while the client isn't listening are held back until the next operation
+/// starts.
+pub struct TunnelLogger<W: Write>(Arc<Tunnel<W>>);
+
+impl<W: Write> Clone for TunnelLogger<W> {
+  fn clone(&self) -> Self {
+    Self(Arc::clone(&self.0))
+  }
+}
+
+// a panic while holding one of these can't leave the connection in a worse
+// state than it's already in
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+  m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<W: Write> TunnelLogger<W> {
+  pub fn new(sink: W) -> Self {
+    Self(Arc::new(Tunnel {
+      can_send: Mutex::new(false),
+      pending: Mutex::new(vec![]),
+      sink: Mutex::new(archive::Sink::new(sink)),
+    }))
+  }
+
+  /// The connection to the client. Nothing may be logged while this is held
+  /// during an operation, since log messages are written to it as well.
+  fn sink(&self) -> MutexGuard<archive::Sink<W>> {
+    lock(&self.0.sink)
+  }
+
+  fn send(&self, msg: String) -> io::Result<()> {
+    let can_send = lock(&self.0.can_send);
+    if *can_send {
+      let mut sink = self.sink();
+      sink.write_usize(STDERR_NEXT)?;
+      sink.write_tag(msg)?;
+      sink.flush()?;
+    } else {
+      lock(&self.0.pending).push(msg);
+    }
+    Ok(())
+  }
+
+  fn start_work(&self) -> io::Result<()> {
+    let mut can_send = lock(&self.0.can_send);
+    *can_send = true;
+    let mut sink = self.sink();
+    for msg in lock(&self.0.pending).drain(..) {
+      sink.write_usize(STDERR_NEXT)?;
+      sink.write_tag(msg)?;
+    }
+    sink.flush()
+  }
+
+  fn stop_work(&self, error: Option<&anyhow::Error>) -> io::Result<()> {
+    let mut can_send = lock(&self.0.can_send);
+    *can_send = false;
+    let mut sink = self.sink();
+    match error {
+      None => sink.write_usize(STDERR_LAST)?,
+      Some(e) => {
+        sink.write_usize(STDERR_ERROR)?;
+        sink.write_tag(format!("{:#}", e))?;
+        sink.write_usize(1)?;
+      }
+    }
+    sink.flush()
+  }
+
+  /// Run `f`, forwarding its log output to the client. Errors are reported to
+  /// the client rather than ending the connection, in which case `None` is
+  /// returned and no reply should be sent.
+  fn work<T, F: FnOnce() -> Result<T>>(&self, f: F) -> io::Result<Option<T>> {
+    self.start_work()?;
+    let result = f();
+    self.stop_work(result.as_ref().err())?;
+    Ok(result.ok())
+  }
+}
+
+impl<W: Write + Send + 'static> Drain for TunnelLogger<W> {
+  type Err = io::Error;
+  type Ok = ();
+
+  fn log(&self, record: &Record, _: &OwnedKVList) -> io::Result<()> {
+    self.send(format!("{}\n", record.msg()))
+  }
+}
+
+/// Lets the store read data that the client sends on request, as used by
+/// `wopAddToStoreNar`.
+struct TunnelSource<'a, R, W: Write> {
+  from: &'a mut R,
+  logger: &'a TunnelLogger<W>,
+}
+
+impl<'a, R: Read, W: Write> Read for TunnelSource<'a, R, W> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    {
+      let mut sink = self.logger.sink();
+      sink.write_usize(STDERR_READ)?;
+      sink.write_usize(buf.len())?;
+      sink.flush()?;
+    }
+    let data = archive::read_bytes(self.from)
+      .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:#}", e)))?;
+    if data.len() > buf.len() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "client sent more data than was requested",
+      ));
+    }
+    buf[..data.len()].copy_from_slice(&data);
+    Ok(data.len())
+  }
+}
+
+/// Whether `user`, a member of `groups`, is matched by `list`. Entries are
+/// either user names, `@group` names, or `*` to match everyone.
+pub fn matches_user<S: AsRef<str>>(list: &[S], user: &str, groups: &[String]) -> bool {
+  list.iter().any(|entry| match entry.as_ref() {
+    "*" => true,
+    entry => match entry.strip_prefix('@') {
+      Some(group) => groups.iter().any(|g| g == group),
+      None => entry == user,
+    },
+  })
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+  e.downcast_ref::<io::Error>()
+    .map_or(false, |e| e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// Serve a single client until it disconnects. All log output of the current
+/// process is sent to the client from now on. `trusted` clients may skip
+/// signature checks and repair paths.
+pub fn process_connection<R: Read, W: Write + Send + 'static>(
+  store: &LocalStore,
+  mut from: R,
+  to: W,
+  trusted: bool,
+) -> Result<()> {
+  let logger = TunnelLogger::new(to);
+
+  ensure!(read_num(&mut from)? == WORKER_MAGIC_1, "protocol mismatch");
+  {
+    let mut sink = logger.sink();
+    sink.write_usize(WORKER_MAGIC_2)?;
+    sink.write_usize(PROTOCOL_VERSION)?;
+    sink.flush()?;
+  }
+
+  let client_version = read_num(&mut from)?;
+  // the oldest clients upstream still serves; newer fields are only read
+  // from and sent to clients that know about them
+  ensure!(
+    protocol_major(client_version) == protocol_major(PROTOCOL_VERSION)
+      && protocol_minor(client_version) >= 0x0a,
+    "the Nix client version {}.{} is too old",
+    client_version >> 8,
+    protocol_minor(client_version)
+  );
+  let client_minor = protocol_minor(client_version);
+
+  // CPU affinity, which we don't bother with
+  if client_minor >= 14 && read_bool(&mut from)? {
+    read_num(&mut from)?;
+  }
+  // obsolete reserveSpace flag
+  if client_minor >= 11 {
+    read_bool(&mut from)?;
+  }
+
+  crate::logger::redirect(logger.clone());
+  logger.work(|| Ok(()))?;
+
+  loop {
+    let op = match read_num(&mut from) {
+      Ok(op) => op,
+      Err(e) if is_eof(&e) => break,
+      Err(e) => return Err(e),
+    };
+    let op = Op::try_from(op)?;
+    trace!("performing daemon worker op: {:?}", op);
+    perform_op(store, &logger, &mut from, op, client_minor, trusted)
+      .with_context(|| format!("while performing {:?}", op))?;
+  }
+
+  Ok(())
+}
+
+fn perform_op<R: Read, W: Write>(
+  store: &LocalStore,
+  logger: &TunnelLogger<W>,
+  from: &mut R,
+  op: Op,
+  client_minor: usize,
+  trusted: bool,
+) -> Result<()> {
+  // every arm reads its arguments, does its work between start_work and
+  // stop_work, then writes its reply if the work succeeded
+  let reply = |f: &mut dyn FnMut(&mut archive::Sink<W>) -> io::Result<()>| -> Result<()> {
+    let mut sink = logger.sink();
+    f(&mut sink)?;
+    sink.flush()?;
+    Ok(())
+  };
+
+  match op {
+    Op::IsValidPath => {
+      let path = read_store_path(store, from)?;
+      if let Some(valid) = logger.work(|| store.is_valid_path(&path))? {
+        reply(&mut |s| s.write_usize(valid as usize))?;
+      }
+    }
+
+    Op::QueryValidPaths => {
+      let paths = read_store_paths(store, from)?;
+      let valid = logger.work(|| {
+        let mut valid = BTreeSet::new();
+        for path in paths {
+          if store.is_valid_path(&path)? {
+            valid.insert(path);
+          }
+        }
+        Ok(valid)
+      })?;
+      if let Some(valid) = valid {
+        reply(&mut |s| write_store_paths(store, s, &valid))?;
+      }
+    }
+
+    Op::QueryAllValidPaths => {
+      if let Some(paths) = logger.work(|| store.query_all_valid_paths())? {
+        reply(&mut |s| write_store_paths(store, s, &paths))?;
+      }
+    }
+
+    Op::QueryPathInfo => {
+      let path = read_store_path(store, from)?;
+      let info = logger.work(|| {
+        let info = store.query_path_info(&path)?;
+        // older clients can't be told that a path is invalid other than by
+        // an error
+        ensure!(
+          info.is_some() || client_minor >= 17,
+          "path {} is not valid",
+          store.print_store_path(&path)
+        );
+        Ok(info)
+      })?;
+      if let Some(info) = info {
+        reply(&mut |s| {
+          if client_minor >= 17 {
+            s.write_usize(info.is_some() as usize)?;
+          }
+          match &info {
+            Some(info) => write_path_info(store, s, info, client_minor),
+            None => Ok(()),
+          }
+        })?;
+      }
+    }
+
+    Op::QueryReferrers => {
+      let path = read_store_path(store, from)?;
+      if let Some(paths) = logger.work(|| store.query_referrers(&path))? {
+        reply(&mut |s| write_store_paths(store, s, &paths))?;
+      }
+    }
+
+    Op::QueryDerivationOutputs => {
+      let path = read_store_path(store, from)?;
+      let outputs = logger.work(|| {
+        Ok(
+          store
+            .query_derivation_outputs(&path)?
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect::<BTreeSet<_>>(),
+        )
+      })?;
+      if let Some(outputs) = outputs {
+        reply(&mut |s| write_store_paths(store, s, &outputs))?;
+      }
+    }
+
+    Op::QueryValidDerivers => {
+      let path = read_store_path(store, from)?;
+      if let Some(paths) = logger.work(|| store.query_valid_derivers(&path))? {
+        reply(&mut |s| write_store_paths(store, s, &paths))?;
+      }
+    }
+
+    Op::AddToStore => {
+      let name = read_string(from)?;
+      let fixed = read_bool(from)?;
+      let recursive = read_bool(from)?;
+      let hash_type = read_string(from)?;
+      // the contents always arrive as a NAR, so unpack them before anything
+      // else is said on the connection
+      let tmpdir = tempfile::tempdir()?;
+      let tmp_path = tmpdir.path().join("x");
+      archive::restore_path(&tmp_path, &mut *from)?;
+
+      let path = logger.work(|| {
+        // old clients left `fixed' unset to mean a recursive SHA-256 hash
+        let (method, hash_type) = if fixed {
+          let method = if recursive {
+            FileIngestionMethod::Recursive
+          } else {
+            FileIngestionMethod::Flat
+          };
+          (method, hash_type.parse::<HashType>()?)
+        } else {
+          (FileIngestionMethod::Recursive, HashType::SHA256)
+        };
+        store.add_to_store_from_path(
+          &name,
+          &tmp_path,
+          method,
+          hash_type,
+          &PathFilter::none(),
+          RepairFlag::NoRepair,
+        )
+      })?;
+      if let Some(path) = path {
+        reply(&mut |s| s.write_tag(store.print_store_path(&path)))?;
+      }
+    }
+
+    Op::AddTextToStore => {
+      let name = read_string(from)?;
+      let contents = read_string(from)?;
+      let references = read_store_paths(store, from)?;
+      let path = logger
+        .work(|| store.add_text_to_store(&name, &contents, references, RepairFlag::NoRepair))?;
+      if let Some(path) = path {
+        reply(&mut |s| s.write_tag(store.print_store_path(&path)))?;
+      }
+    }
+
+    Op::AddToStoreNar => {
+      let path = read_store_path(store, from)?;
+      let mut info = read_path_info(store, from, path)?;
+      let repair = read_bool(from)?;
+      let dont_check_sigs = read_bool(from)?;
+      // older clients send the NAR straight away instead of on request
+      let nar = if client_minor < 21 {
+        let mut nar = vec![];
+        archive::copy_nar(&mut *from, &mut nar)?;
+        Some(nar)
+      } else {
+        None
+      };
+
+      // only trusted users get to vouch for a path's contents
+      info.ultimate &= trusted;
+      let check_sigs = if dont_check_sigs && trusted {
+        CheckSigsFlag::NoCheckSigs
+      } else {
+        CheckSigsFlag::CheckSigs
+      };
+      let repair = if repair {
+        RepairFlag::Repair
+      } else {
+        RepairFlag::NoRepair
+      };
+
+      logger.work(|| match &nar {
+        Some(nar) => store.add_to_store_from_source(info, nar.as_slice(), repair, check_sigs),
+        None => {
+          let source = TunnelSource {
+            from: &mut *from,
+            logger,
+          };
+          store.add_to_store_from_source(info, source, repair, check_sigs)
+        }
+      })?;
+    }
+
+    Op::BuildPaths => {
+      let paths = read_strings(from)?
+        .iter()
+        .map(|p| store.parse_path_with_outputs(p))
+        .collect::<Result<Vec<_>>>()?;
+      let mode = if client_minor >= 15 {
+        read_num(from)?
+      } else {
+        BuildMode::Normal as usize
+      };
+      let done = logger.work(|| {
+        match mode {
+          x if x == BuildMode::Normal as usize => store.build_paths(paths)?,
+          x if x == BuildMode::Repair as usize => {
+            ensure!(
+              trusted,
+              "repairing is not allowed because you are not in `trusted-users'"
+            );
+            let mut worker = Worker::with_store(store);
+            worker.set_requester(store.requester());
+            for path in &paths {
+              worker.add_repair(&path.path)?;
+            }
+            worker.build()?
+          }
+          x if x == BuildMode::Check as usize => {
+            let mut worker = Worker::with_store(store);
+            worker.set_requester(store.requester());
+            for path in &paths {
+              worker.add_check(&path.path)?;
+            }
+            worker.build()?
+          }
+          x => bail!("invalid build mode {}", x),
+        }
+        Ok(())
+      })?;
+      if done.is_some() {
+        reply(&mut |s| s.write_usize(1))?;
+      }
+    }
+
+    Op::EnsurePath => {
+      let path = read_store_path(store, from)?;
+      if logger
+        .work(|| crate::build::ensure_path(store, &path))?
+        .is_some()
+      {
+        reply(&mut |s| s.write_usize(1))?;
+      }
+    }
+
+    Op::AddTempRoot => {
+      let path = read_store_path(store, from)?;
+      if logger.work(|| store.add_temp_root(&path))?.is_some() {
+        reply(&mut |s| s.write_usize(1))?;
+      }
+    }
+
+    Op::SyncWithGC => {
+      // temp roots are written synchronously, so there's nothing to wait for
+      if logger.work(|| Ok(()))?.is_some() {
+        reply(&mut |s| s.write_usize(1))?;
+      }
+    }
+
+    Op::SetOptions => {
+      let keep_failed = read_bool(from)?;
+      let keep_going = read_bool(from)?;
+      let try_fallback = read_bool(from)?;
+      // verbosity
+      read_num(from)?;
+      let max_build_jobs = read_num(from)?;
+      let max_silent_time = read_num(from)?;
+      // obsolete useBuildHook flag
+      read_num(from)?;
+      // the verbosity of build output, which is only verbose at the error level
+      let verbose_build = read_num(from)? == 0;
+      // obsolete logType and printBuildTrace
+      read_num(from)?;
+      read_num(from)?;
+      let build_cores = read_num(from)?;
+      let use_substitutes = read_bool(from)?;
+      let mut overrides = vec![];
+      if client_minor >= 12 {
+        for _ in 0..read_num(from)? {
+          let name = read_string(from)?;
+          let value = read_string(from)?;
+          overrides.push((name, value));
+        }
+      }
+
+      // this process only serves this client, so its settings are the client's
+      logger.work(|| {
+        Settings::update(|s| {
+          s.keep_failed = keep_failed;
+          s.keep_going = keep_going;
+          s.try_fallback = try_fallback;
+          s.max_build_jobs = max_build_jobs;
+          s.max_silent_time = Some(Duration::from_secs(max_silent_time as u64));
+          s.verbose_build = verbose_build;
+          s.build_cores = build_cores;
+          s.use_substitutes = use_substitutes;
+          for (name, value) in &overrides {
+            if trusted || name == "timeout" || name == "build-timeout" {
+              if let Err(e) = s.set(name, value) {
+                warn!("ignoring setting `{}': {:#}", name, e);
+              }
+            } else {
+              warn!(
+                "ignoring the restricted setting `{}', because you are not a trusted user",
+                name
+              );
+            }
+          }
+        });
+        Ok(())
+      })?;
+    }
+
+    Op::NarFromPath => {
+      let path = read_store_path(store, from)?;
+      let valid = logger.work(|| {
+        ensure!(
+          store.is_valid_path(&path)?,
+          "path {} is not valid",
+          store.print_store_path(&path)
+        );
+        Ok(())
+      })?;
+      if valid.is_some() {
+        let mut sink = logger.sink();
+        store.nar_from_path(&path, &mut *sink)?;
+        sink.flush()?;
+      }
+    }
+
+    Op::AddSignatures => {
+      let path = read_store_path(store, from)?;
+      let sigs = read_strings(from)?;
+      let done = logger.work(|| {
+        ensure!(trusted, "you are not privileged to add signatures");
+        store.add_signatures(&path, &sigs)
+      })?;
+      if done.is_some() {
+        reply(&mut |s| s.write_usize(1))?;
+      }
+    }
+
+    Op::OptimiseStore => {
+      if logger.work(|| store.optimise_store())?.is_some() {
+        reply(&mut |s| s.write_usize(1))?;
+      }
+    }
+
+    Op::VerifyStore => {
+      let check_contents = read_bool(from)?;
+      let repair = read_bool(from)?;
+      let errors = logger.work(|| {
+        ensure!(!repair || trusted, "you are not privileged to repair paths");
+        store.verify_store(
+          check_contents,
+          if repair {
+            RepairFlag::Repair
+          } else {
+            RepairFlag::NoRepair
+          },
+        )
+      })?;
+      if let Some(errors) = errors {
+        reply(&mut |s| s.write_usize(errors as usize))?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_matches_user() {
+    let groups = vec!["wheel".to_string(), "users".to_string()];
+    assert!(matches_user(&["*"], "alice", &groups));
+    assert!(matches_user(&["root", "alice"], "alice", &groups));
+    assert!(matches_user(&["@wheel"], "alice", &groups));
+    assert!(!matches_user(&["@nixbld", "bob"], "alice", &groups));
+    assert!(!matches_user::<&str>(&[], "alice", &groups));
+  }
+}
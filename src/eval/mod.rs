@@ -1,7 +1,7 @@
 use crate::{
   arena::Arena,
   prelude::{Path, *},
-  store::LocalStore,
+  store::{open_store, AnyStore},
   syntax::expr::{self, *},
 };
 use builtins::strings::coerce_to_string;
@@ -46,7 +46,7 @@ struct AssertFailure {
   message: String,
 }
 
-pub struct Eval<S: Store = LocalStore> {
+pub struct Eval<S: Store = AnyStore> {
   items: Arena<Thunk>,
   expr: Arena<Expr>,
   toplevel: StaticScope,
@@ -67,6 +67,10 @@ impl Eval {
   }
 
   pub fn with_config(config: Config) -> Result<Self> {
+    Self::with_store(config, open_store()?)
+  }
+
+  pub fn with_store(config: Config, store: AnyStore) -> Result<Self> {
     let mut this = Self {
       items: Default::default(),
       expr: Default::default(),
@@ -76,7 +80,7 @@ impl Eval {
       file_ids: Default::default(),
       writer: StandardStream::stderr(ColorChoice::Auto),
       config,
-      store: Arc::new(LocalStore::open()?),
+      store: Arc::new(store),
       trace: Default::default(),
     };
     builtins::init_primops(&mut this)?;
@@ -1,8 +1,14 @@
 use super::*;
+use crate::store::MemoryStore;
+
+fn eval() -> Eval {
+  Settings::init_for_tests();
+  Eval::with_store(Default::default(), AnyStore::Memory(MemoryStore::new())).unwrap()
+}
 
 macro_rules! assert_eval {
   ($l:literal, $p:pat) => {{
-    let eval = Eval::new().unwrap();
+    let eval = eval();
     assert_matches::assert_matches!(eval.value_of(eval.load_inline($l)?), $p)
   }};
 }
@@ -21,7 +27,7 @@ fn test_rec_order() -> Result<()> {
 
 #[test]
 fn test_foldl() -> Result<()> {
-  let e = Eval::new().unwrap();
+  let e = eval();
   let expr = e.load_inline(r#"builtins.foldl' (x: y: "${x}-${y}") "foo" ["bar" "baz" "qux"]"#)?;
   assert_eq!(e.value_string_of(expr)?, "foo-bar-baz-qux");
   Ok(())
@@ -29,7 +35,7 @@ fn test_foldl() -> Result<()> {
 
 #[test]
 fn test_replace() -> Result<()> {
-  let e = Eval::new().unwrap();
+  let e = eval();
   let expr = e.load_inline(
     r#"
       builtins.replaceStrings ["-" "."] ["_" "_"] "x86_64-unknown-linux-gnu"
@@ -41,7 +47,7 @@ fn test_replace() -> Result<()> {
 
 #[test]
 fn test_unindent() -> Result<()> {
-  let e = Eval::new().unwrap();
+  let e = eval();
   let expr = e.load_inline(
     r#"
       ''
@@ -54,3 +60,15 @@ fn test_unindent() -> Result<()> {
   assert_eq!(e.value_string_of(expr)?, "foo\nbar\nbaz\n");
   Ok(())
 }
+
+#[test]
+fn test_derivation_in_memory() -> Result<()> {
+  let e = eval();
+  let expr = e.load_inline(
+    r#"(derivation { name = "foo"; system = "x86_64-linux"; builder = "/bin/sh"; }).drvPath"#,
+  )?;
+  let drv_path = e.store.parse_store_path(e.value_with_context_of(expr)?.0)?;
+  assert!(e.store.is_valid_path(&drv_path)?);
+  assert_eq!(e.store.read_derivation(&drv_path)?.name, "foo");
+  Ok(())
+}
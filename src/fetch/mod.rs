@@ -124,8 +124,11 @@ pub fn download_tarball<S: Store>(
   Ok(new_path)
 }
 
-pub fn fetchurl(derivation: &Derivation, progress: &ProgressBar) -> Result<Hash> {
-  let store_path = derivation.get_env("out")?;
+pub fn fetchurl(
+  derivation: &Derivation,
+  store_path: &Path,
+  progress: &ProgressBar,
+) -> Result<Hash> {
   let main_url = derivation.get_env("url")?;
   let unpack = derivation.env.get("unpack").map_or(false, |x| x == "1");
   let expected_hash = derivation
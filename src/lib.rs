@@ -21,16 +21,19 @@
 pub mod archive;
 pub mod arena;
 pub mod build;
+pub mod daemon;
 pub mod derivation;
 pub mod eval;
 pub mod fetch;
 pub mod globals;
 pub mod hash;
 pub mod logger;
+pub mod nar_info;
 pub mod path;
 pub mod path_info;
 mod prelude;
 pub mod settings;
+pub mod signing;
 pub mod sqlite;
 pub mod store;
 pub mod sync;
@@ -40,6 +43,6 @@ pub mod util;
 pub use settings::Settings;
 pub use store::Store;
 
-pub fn settings() -> &'static Settings {
+pub fn settings() -> std::sync::Arc<Settings> {
   Settings::get()
 }
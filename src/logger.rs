@@ -1,8 +1,11 @@
 use crate::prelude::Result;
 use indicatif::ProgressBar;
-use slog::{Discard, Drain, FnValue, OwnedKVList, Record, KV};
+use slog::{Discard, Drain, FnValue, OwnedKVList, Record, SendSyncRefUnwindSafeDrain, KV};
 use slog_atomic::{AtomicSwitch, AtomicSwitchCtrl};
-use std::sync::Mutex;
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Mutex,
+};
 
 lazy_static! {
   // static ref PROGRESS_LOG: Mutex<Option<ProgressBar>> = Mutex::new(None);
@@ -10,6 +13,9 @@ lazy_static! {
     AtomicSwitch::new(Discard.map_err(|_| unreachable!())).ctrl();
 }
 
+// set once log output has been sent somewhere other than the terminal
+static REDIRECTED: AtomicBool = AtomicBool::new(false);
+
 struct ProgressLogger(ProgressBar);
 
 struct ProgressFormatter<'a> {
@@ -47,16 +53,30 @@ impl Drain for ProgressLogger {
 }
 
 pub fn set(progress: ProgressBar) {
+  if REDIRECTED.load(Ordering::SeqCst) {
+    return;
+  }
   DRAIN_SWITCH.set(ProgressLogger(progress))
 }
 
 pub fn reset() {
+  if REDIRECTED.load(Ordering::SeqCst) {
+    return;
+  }
   DRAIN_SWITCH.set(
     Mutex::new(slog_term::term_full())
       .map_err(|f| std::io::Error::new(std::io::ErrorKind::Other, f)),
   );
 }
 
+/// Send all further log output to `drain`. Progress bars created by builds
+/// won't take it over again, which is what the daemon needs to keep its
+/// client informed.
+pub fn redirect<D: SendSyncRefUnwindSafeDrain<Ok = (), Err = std::io::Error> + 'static>(drain: D) {
+  REDIRECTED.store(true, Ordering::SeqCst);
+  DRAIN_SWITCH.set(drain);
+}
+
 pub fn init() -> Result<()> {
   self::reset();
 
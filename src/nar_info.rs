@@ -0,0 +1,169 @@
+use crate::{prelude::*, util::compression::Compression};
+use std::collections::BTreeSet;
+
+/// The contents of a `.narinfo` file: a [`ValidPathInfo`] plus the
+/// information needed to fetch the NAR from a binary cache.
+#[derive(Clone, Debug)]
+pub struct NarInfo {
+  pub path_info: ValidPathInfo,
+  pub url: String,
+  pub compression: Compression,
+  pub file_hash: Option<Hash>,
+  pub file_size: Option<u64>,
+  pub system: Option<String>,
+}
+
+impl NarInfo {
+  pub fn new(path_info: ValidPathInfo, url: String, compression: Compression) -> Self {
+    Self {
+      path_info,
+      url,
+      compression,
+      file_hash: None,
+      file_size: None,
+      system: None,
+    }
+  }
+
+  pub fn parse<S: Store + ?Sized>(store: &S, input: &str, whence: &str) -> Result<Self> {
+    let corrupt = |reason: &str| anyhow!("NAR info file `{}' is corrupt: {}", whence, reason);
+
+    let mut store_path = None;
+    let mut url = None;
+    let mut compression = None;
+    let mut file_hash = None;
+    let mut file_size = None;
+    let mut nar_hash = None;
+    let mut nar_size = None;
+    let mut references = BTreeSet::new();
+    let mut deriver = None;
+    let mut system = None;
+    let mut signatures = BTreeSet::new();
+    let mut content_addressed = None;
+
+    for line in input.lines().filter(|x| !x.is_empty()) {
+      let (name, value) = break_str(line, ": ").ok_or_else(|| corrupt(line))?;
+      match name {
+        "StorePath" => store_path = Some(store.parse_store_path(value)?),
+        "URL" => url = Some(value.to_string()),
+        "Compression" => compression = Some(value.parse()?),
+        "FileHash" => file_hash = Some(Hash::decode(value)?),
+        "FileSize" => file_size = Some(value.parse().map_err(|_| corrupt(line))?),
+        "NarHash" => nar_hash = Some(Hash::decode(value)?),
+        "NarSize" => nar_size = Some(value.parse().map_err(|_| corrupt(line))?),
+        "References" => {
+          references = value
+            .split_whitespace()
+            .map(StorePath::from_base_name)
+            .collect::<Result<_>>()?
+        }
+        "Deriver" => {
+          if value != "unknown-deriver" {
+            deriver = Some(StorePath::from_base_name(value)?)
+          }
+        }
+        "System" => system = Some(value.to_string()),
+        "Sig" => {
+          signatures.insert(value.to_string());
+        }
+        "CA" => content_addressed = Some(value.to_string()),
+        _ => {}
+      }
+    }
+
+    let store_path = store_path.ok_or_else(|| corrupt("missing StorePath"))?;
+    let url = url.ok_or_else(|| corrupt("missing URL"))?;
+    let nar_hash = nar_hash.ok_or_else(|| corrupt("missing NarHash"))?;
+    let nar_size = nar_size.ok_or_else(|| corrupt("missing NarSize"))?;
+
+    let mut path_info = ValidPathInfo::new(store_path, nar_hash);
+    path_info.nar_size = Some(nar_size);
+    path_info.references = references;
+    path_info.deriver = deriver;
+    path_info.signatures = signatures;
+    path_info.content_addressed = content_addressed;
+
+    Ok(Self {
+      path_info,
+      url,
+      // NARs were compressed with bzip2 before this field existed
+      compression: compression.unwrap_or(Compression::Bzip2),
+      file_hash,
+      file_size,
+      system,
+    })
+  }
+
+  pub fn unparse<S: Store + ?Sized>(&self, store: &S) -> String {
+    let info = &self.path_info;
+    let mut lines = vec![
+      format!("StorePath: {}", store.print_store_path(&info.store_path)),
+      format!("URL: {}", self.url),
+      format!("Compression: {}", self.compression),
+    ];
+    if let Some(h) = &self.file_hash {
+      lines.push(format!(
+        "FileHash: {}",
+        h.encode_with_type(Encoding::Base32)
+      ));
+    }
+    if let Some(n) = self.file_size {
+      lines.push(format!("FileSize: {}", n));
+    }
+    lines.push(format!(
+      "NarHash: {}",
+      info.nar_hash.encode_with_type(Encoding::Base32)
+    ));
+    lines.push(format!("NarSize: {}", info.nar_size.unwrap_or(0)));
+    lines.push(format!(
+      "References: {}",
+      itertools::join(&info.references, " ")
+    ));
+    if let Some(d) = &info.deriver {
+      lines.push(format!("Deriver: {}", d));
+    }
+    if let Some(sys) = &self.system {
+      lines.push(format!("System: {}", sys));
+    }
+    for sig in &info.signatures {
+      lines.push(format!("Sig: {}", sig));
+    }
+    if let Some(ca) = &info.content_addressed {
+      lines.push(format!("CA: {}", ca));
+    }
+
+    let mut s = lines.join("\n");
+    s.push('\n');
+    s
+  }
+}
+
+impl PathInfo for NarInfo {
+  fn store_path(&self) -> &StorePath {
+    &self.path_info.store_path
+  }
+
+  fn nar_hash(&self) -> &Hash {
+    &self.path_info.nar_hash
+  }
+
+  fn references(&self) -> &BTreeSet<StorePath> {
+    &self.path_info.references
+  }
+
+  fn deriver(&self) -> Option<&StorePath> {
+    self.path_info.deriver.as_ref()
+  }
+
+  fn nar_size(&self) -> Option<u64> {
+    self.path_info.nar_size
+  }
+
+  fn signatures(&self) -> &BTreeSet<String> {
+    &self.path_info.signatures
+  }
+
+  fn content_addressed(&self) -> Option<&str> {
+    self.path_info.content_addressed.as_deref()
+  }
+}
@@ -3,7 +3,33 @@ use std::{collections::BTreeSet, fmt::Debug, time::SystemTime};
 
 pub trait PathInfo: Send + Sync + Debug {
   fn store_path(&self) -> &StorePath;
+  fn nar_hash(&self) -> &Hash;
   fn references(&self) -> &BTreeSet<StorePath>;
+  fn deriver(&self) -> Option<&StorePath>;
+  fn nar_size(&self) -> Option<u64>;
+  fn signatures(&self) -> &BTreeSet<String>;
+  fn content_addressed(&self) -> Option<&str>;
+
+  /// When the path was registered, if this info came from a store that keeps
+  /// track of it.
+  fn registration_time(&self) -> Option<SystemTime> {
+    None
+  }
+
+  /// Whether the path was built locally rather than copied from elsewhere.
+  fn ultimate(&self) -> bool {
+    false
+  }
+
+  fn to_valid_path_info(&self) -> ValidPathInfo {
+    let mut info = ValidPathInfo::new(self.store_path().clone(), *self.nar_hash());
+    info.references = self.references().clone();
+    info.deriver = self.deriver().cloned();
+    info.nar_size = self.nar_size();
+    info.signatures = self.signatures().clone();
+    info.content_addressed = self.content_addressed().map(String::from);
+    info
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -52,7 +78,122 @@ impl PathInfo for ValidPathInfo {
     &self.store_path
   }
 
+  fn nar_hash(&self) -> &Hash {
+    &self.nar_hash
+  }
+
   fn references(&self) -> &BTreeSet<StorePath> {
     &self.references
   }
+
+  fn deriver(&self) -> Option<&StorePath> {
+    self.deriver.as_ref()
+  }
+
+  fn nar_size(&self) -> Option<u64> {
+    self.nar_size
+  }
+
+  fn signatures(&self) -> &BTreeSet<String> {
+    &self.signatures
+  }
+
+  fn content_addressed(&self) -> Option<&str> {
+    self.content_addressed.as_deref()
+  }
+
+  fn registration_time(&self) -> Option<SystemTime> {
+    Some(self.registration_time)
+  }
+
+  fn ultimate(&self) -> bool {
+    self.ultimate
+  }
+}
+
+/// The content address of a path added with
+/// [`add_text_to_store`](crate::store::Store::add_text_to_store), whose
+/// contents hash to `hash`.
+pub fn text_ca(hash: &Hash) -> String {
+  format!("text:{}", hash.encode_with_type(Encoding::Base32))
+}
+
+/// The content address of a fixed-output path whose contents (or NAR, if
+/// `recursive`) hash to `hash`.
+pub fn fixed_output_ca(recursive: bool, hash: &Hash) -> String {
+  format!(
+    "fixed:{}{}",
+    if recursive { "r:" } else { "" },
+    hash.encode_with_type(Encoding::Base32)
+  )
+}
+
+/// The JSON representation of `info` printed by `nix path-info --json`.
+pub fn path_info_json<S: Store>(
+  store: &S,
+  info: &dyn PathInfo,
+  closure_size: Option<u64>,
+) -> Result<serde_json::Value> {
+  let mut json = serde_json::json!({
+    "path": store.print_store_path(info.store_path()),
+    "narHash": info.nar_hash().encode_with_type(Encoding::Base32),
+    "narSize": info.nar_size(),
+    "references": info
+      .references()
+      .iter()
+      .map(|r| store.print_store_path(r))
+      .collect::<Vec<_>>(),
+    "signatures": info.signatures(),
+  });
+  if let Some(time) = info.registration_time() {
+    json["registrationTime"] = time
+      .duration_since(SystemTime::UNIX_EPOCH)?
+      .as_secs()
+      .into();
+  }
+  if info.ultimate() {
+    json["ultimate"] = true.into();
+  }
+  if let Some(size) = closure_size {
+    json["closureSize"] = size.into();
+  }
+  if let Some(d) = info.deriver() {
+    json["deriver"] = store.print_store_path(d).into();
+  }
+  if let Some(ca) = info.content_addressed() {
+    json["ca"] = ca.into();
+  }
+  Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{LocalStore, RepairFlag};
+
+  #[test]
+  fn test_path_info_json() -> Result<()> {
+    Settings::init_for_tests();
+    let root = tempfile::tempdir()?;
+    let s = LocalStore::open_chroot(root.path())?;
+    let dep = s.add_text_to_store("dep", "foo", None, RepairFlag::NoRepair)?;
+    let path = StorePath::from_base_name("7rjj86a15146cq1d3qy068lml7n8ykzm-foo")?;
+
+    let mut info = ValidPathInfo::new(path.clone(), Hash::hash_str("bar", HashType::SHA256));
+    info.references.insert(dep.clone());
+    info.registration_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    info.ultimate = true;
+    s.register_valid_path(info)?;
+
+    let info = s.get_path_info(&path)?.unwrap();
+    let json = path_info_json(&s, &*info, None)?;
+    assert_eq!(json["path"], s.print_store_path(&path));
+    assert_eq!(
+      json["references"],
+      serde_json::json!([s.print_store_path(&dep)])
+    );
+    assert_eq!(json["registrationTime"], 1_600_000_000);
+    assert_eq!(json["ultimate"], true);
+    Ok(())
+  }
 }
@@ -11,6 +11,53 @@ pub struct CliOptions {
     parse(try_from_str = parse_jobs)
   )]
   pub build_max_jobs: Option<usize>,
+
+  #[structopt(long, help = "Build from source if substitution fails.")]
+  pub fallback: bool,
+
+  #[structopt(
+    long = "no-substitutes",
+    help = "Don't download outputs from substituters."
+  )]
+  pub no_substitutes: bool,
+
+  #[structopt(
+    long,
+    help = "Space-separated list of substituter URIs to use instead of the default ones."
+  )]
+  pub substituters: Option<String>,
+
+  #[structopt(
+    long = "trusted-public-keys",
+    help = "Space-separated list of public keys to trust instead of the default ones."
+  )]
+  pub trusted_public_keys: Option<String>,
+
+  #[structopt(
+    long = "secret-key-files",
+    help = "Space-separated list of secret key files with which to sign locally built paths."
+  )]
+  pub secret_key_files: Option<String>,
+
+  #[structopt(
+    long = "no-require-sigs",
+    help = "Accept paths from substituters and other stores without a valid signature."
+  )]
+  pub no_require_sigs: bool,
+
+  #[structopt(
+    short = "K",
+    long = "keep-failed",
+    help = "Keep the build directories of failed builds."
+  )]
+  pub keep_failed: bool,
+
+  #[structopt(
+    short = "k",
+    long = "keep-going",
+    help = "Keep building derivations that don't depend on a failed one."
+  )]
+  pub keep_going: bool,
 }
 
 fn parse_jobs(s: &str) -> Result<usize, <usize as std::str::FromStr>::Err> {
@@ -31,5 +78,37 @@ impl Settings {
     if let Some(b) = f.build_max_jobs {
       self.max_build_jobs = b;
     }
+
+    if f.fallback {
+      self.try_fallback = true;
+    }
+
+    if f.no_substitutes {
+      self.use_substitutes = false;
+    }
+
+    if let Some(s) = f.substituters {
+      self.substituters = s.split_whitespace().map(String::from).collect();
+    }
+
+    if let Some(s) = f.trusted_public_keys {
+      self.trusted_public_keys = s.split_whitespace().map(String::from).collect();
+    }
+
+    if let Some(s) = f.secret_key_files {
+      self.secret_key_files = s.split_whitespace().map(String::from).collect();
+    }
+
+    if f.no_require_sigs {
+      self.require_sigs = false;
+    }
+
+    if f.keep_failed {
+      self.keep_failed = true;
+    }
+
+    if f.keep_going {
+      self.keep_going = true;
+    }
   }
 }
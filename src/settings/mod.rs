@@ -1,13 +1,16 @@
 #![allow(unused_doc_comments)] // false positive
 
+use anyhow::{bail, Result};
 pub use cli::CliOptions;
 use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
 use std::{
   borrow::Borrow,
   cmp,
   collections::HashSet,
   env,
   path::{Path, PathBuf},
+  sync::Arc,
   time::Duration,
 };
 use unix::unistd;
@@ -16,7 +19,7 @@ mod cli;
 
 static NIXOS_CACHE_PUBKEY: &str = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
 
-static SETTINGS: OnceCell<Settings> = OnceCell::new();
+static SETTINGS: OnceCell<RwLock<Arc<Settings>>> = OnceCell::new();
 
 #[derive(Debug, Clone)]
 pub struct Paths {
@@ -568,7 +571,11 @@ pub enum SandboxMode {
 }
 
 impl Settings {
-  pub fn get() -> &'static Self {
+  pub fn get() -> Arc<Self> {
+    Arc::clone(&Self::lock().read())
+  }
+
+  fn lock() -> &'static RwLock<Arc<Self>> {
     SETTINGS
       .get()
       .expect("internal error: call rix::Settings::init")
@@ -577,11 +584,90 @@ impl Settings {
   pub fn init_with<F: FnOnce(&mut Settings)>(f: F) {
     let mut s = Settings::default();
     f(&mut s);
-    if SETTINGS.set(s).is_err() {
+    if SETTINGS.set(RwLock::new(Arc::new(s))).is_err() {
       panic!("internal error: rix::Settings::init has already been called")
     }
   }
 
+  /// Use the default settings, unless another test got there first.
+  #[cfg(test)]
+  pub(crate) fn init_for_tests() {
+    let _ = SETTINGS.set(RwLock::new(Arc::new(Settings::default())));
+  }
+
+  /// Modify the settings, e.g. to apply the options a client sends to the
+  /// daemon. Anything still holding the old settings keeps seeing them.
+  pub fn update<F: FnOnce(&mut Settings)>(f: F) {
+    f(Arc::make_mut(&mut Self::lock().write()));
+  }
+
+  /// Change the setting called `name` in `nix.conf`. Only the settings that
+  /// can be passed on to the daemon are supported.
+  pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+    fn boolean(value: &str) -> Result<bool> {
+      match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => bail!("`{}' is not a Boolean", value),
+      }
+    }
+    fn seconds(value: &str) -> Result<Option<Duration>> {
+      Ok(Some(Duration::from_secs(value.parse()?)))
+    }
+    fn list(value: &str) -> Vec<String> {
+      value.split_whitespace().map(String::from).collect()
+    }
+
+    match name {
+      "keep-failed" => self.keep_failed = boolean(value)?,
+      "keep-going" => self.keep_going = boolean(value)?,
+      "fallback" => self.try_fallback = boolean(value)?,
+      "max-jobs" => self.max_build_jobs = value.parse()?,
+      "cores" => self.build_cores = value.parse()?,
+      "max-silent-time" => self.max_silent_time = seconds(value)?,
+      "timeout" | "build-timeout" => self.timeout = seconds(value)?,
+      "substitute" => self.use_substitutes = boolean(value)?,
+      "substituters" => self.substituters = list(value),
+      "trusted-public-keys" => self.trusted_public_keys = list(value),
+      "require-sigs" => self.require_sigs = boolean(value)?,
+      "build-repeat" => self.build_repeat = value.parse()?,
+      "enforce-determinism" => self.enforce_determinism = boolean(value)?,
+      "run-diff-hook" => self.run_diff_hook = boolean(value)?,
+      "diff-hook" => self.diff_hook = Some(value.into()).filter(|_| !value.is_empty()),
+      _ => bail!("unknown setting `{}'", name),
+    }
+    Ok(())
+  }
+
+  /// The settings among those accepted by [`Settings::set`] that differ
+  /// from their defaults, as `(name, value)` pairs.
+  pub fn overridden(&self) -> Vec<(&'static str, String)> {
+    fn values(s: &Settings) -> Vec<(&'static str, String)> {
+      let seconds = |d: Option<Duration>| d.map_or(0, |d| d.as_secs()).to_string();
+      vec![
+        ("timeout", seconds(s.timeout)),
+        ("substituters", s.substituters.join(" ")),
+        ("trusted-public-keys", s.trusted_public_keys.join(" ")),
+        ("require-sigs", s.require_sigs.to_string()),
+        ("build-repeat", s.build_repeat.to_string()),
+        ("enforce-determinism", s.enforce_determinism.to_string()),
+        ("run-diff-hook", s.run_diff_hook.to_string()),
+        (
+          "diff-hook",
+          s.diff_hook
+            .as_ref()
+            .map_or_else(String::new, |p| p.display().to_string()),
+        ),
+      ]
+    }
+    values(self)
+      .into_iter()
+      .zip(values(&Self::default()))
+      .filter(|(x, default)| x != default)
+      .map(|(x, _)| x)
+      .collect()
+  }
+
   pub fn init_with_args(s: CliOptions) {
     Self::init_with(|set| set.apply_overrides(s))
   }
@@ -759,5 +845,19 @@ fn get_user_config_files() -> Vec<PathBuf> {
 
 #[test]
 fn init_settings() {
+  Settings::init_for_tests();
   eprintln!("{:#?}", Settings::get());
 }
+
+#[test]
+fn test_set() -> Result<()> {
+  let mut s = Settings::default();
+  s.set("keep-going", "true")?;
+  s.set("timeout", "60")?;
+  assert!(s.keep_going);
+  assert_eq!(s.timeout, Some(Duration::from_secs(60)));
+  assert!(s.set("keep-going", "yes").is_err());
+  assert!(s.set("no-such-setting", "").is_err());
+  assert_eq!(s.overridden(), vec![("timeout", String::from("60"))]);
+  Ok(())
+}
@@ -0,0 +1,319 @@
+//! Ed25519 signatures on store paths, compatible with the keys generated by
+//! `nix-store --generate-binary-cache-key`.
+//!
+//! Keys and signatures are written as `name:base64`. A secret key is the
+//! 32-byte seed followed by the 32-byte public key, the same layout libsodium
+//! uses.
+
+use crate::{prelude::*, store::FileIngestionMethod};
+use crypto::ed25519;
+use parking_lot::Mutex;
+use rand::RngCore;
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt,
+};
+
+const SECRET_KEY_LEN: usize = 64;
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+fn parse_key(input: &str, what: &str, len: usize) -> Result<(String, Vec<u8>)> {
+  let (name, key) = break_str(input.trim(), ':')
+    .filter(|(name, _)| !name.is_empty())
+    .ok_or_else(|| anyhow!("{} is corrupt", what))?;
+  let key = base64::decode(key).with_context(|| format!("while decoding {} `{}'", what, name))?;
+  ensure!(key.len() == len, "{} `{}' has the wrong length", what, name);
+  Ok((name.to_string(), key))
+}
+
+#[derive(Clone)]
+pub struct SecretKey {
+  pub name: String,
+  key: Vec<u8>,
+}
+
+impl fmt::Debug for SecretKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("SecretKey")
+      .field("name", &self.name)
+      .finish()
+  }
+}
+
+impl fmt::Display for SecretKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.name, base64::encode(&self.key))
+  }
+}
+
+impl SecretKey {
+  pub fn parse(input: &str) -> Result<Self> {
+    let (name, key) = parse_key(input, "secret key", SECRET_KEY_LEN)?;
+    Ok(Self { name, key })
+  }
+
+  pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let path = path.as_ref();
+    Self::parse(&fs::read_to_string(path)?)
+      .with_context(|| format!("while reading secret key `{}'", path.display()))
+  }
+
+  /// Generate a fresh key pair from the operating system's random number
+  /// generator.
+  pub fn generate(name: &str) -> Result<Self> {
+    ensure!(
+      !name.is_empty() && !name.contains(':'),
+      "invalid key name `{}'",
+      name
+    );
+    let mut seed = [0; 32];
+    rand::rngs::OsRng.try_fill_bytes(&mut seed)?;
+    let (key, _) = ed25519::keypair(&seed);
+    Ok(Self {
+      name: name.to_string(),
+      key: key.to_vec(),
+    })
+  }
+
+  /// Sign `data`, returning a signature in `name:base64` form.
+  pub fn sign(&self, data: &[u8]) -> String {
+    let sig = ed25519::signature(data, &self.key);
+    format!("{}:{}", self.name, base64::encode(&sig[..]))
+  }
+
+  pub fn to_public_key(&self) -> PublicKey {
+    PublicKey {
+      name: self.name.clone(),
+      key: self.key[32..].to_vec(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+  pub name: String,
+  key: Vec<u8>,
+}
+
+impl fmt::Display for PublicKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.name, base64::encode(&self.key))
+  }
+}
+
+impl PublicKey {
+  pub fn parse(input: &str) -> Result<Self> {
+    let (name, key) = parse_key(input, "public key", PUBLIC_KEY_LEN)?;
+    Ok(Self { name, key })
+  }
+
+  pub fn verify(&self, data: &[u8], sig: &[u8]) -> bool {
+    ed25519::verify(data, &self.key, sig)
+  }
+}
+
+lazy_static! {
+  static ref SECRET_KEYS: Mutex<HashMap<String, SecretKey>> = Default::default();
+}
+
+/// The secret key in `file`, which is only read the first time it's needed.
+pub fn cached_secret_key(file: &str) -> Result<SecretKey> {
+  if let Some(key) = SECRET_KEYS.lock().get(file) {
+    return Ok(key.clone());
+  }
+  let key = SecretKey::read(file)?;
+  SECRET_KEYS.lock().insert(file.to_string(), key.clone());
+  Ok(key)
+}
+
+/// Public keys, indexed by name.
+pub type PublicKeys = BTreeMap<String, PublicKey>;
+
+/// The keys listed in `trusted-public-keys`, plus the public halves of the
+/// keys in `secret-key-files` so that we always trust our own signatures.
+pub fn trusted_public_keys() -> Result<PublicKeys> {
+  let mut keys = PublicKeys::new();
+  for s in &settings().trusted_public_keys {
+    let key = PublicKey::parse(s)?;
+    keys.insert(key.name.clone(), key);
+  }
+  for file in &settings().secret_key_files {
+    // other users' keys are usually unreadable, which is fine for verifying
+    match cached_secret_key(file) {
+      Ok(key) => {
+        let key = key.to_public_key();
+        keys.insert(key.name.clone(), key);
+      }
+      Err(e) => debug!("skipping secret key file `{}': {:#}", file, e),
+    }
+  }
+  Ok(keys)
+}
+
+/// Check whether `sig` is a valid signature of `data` by one of `keys`.
+/// Signatures by unknown keys and malformed signatures are not valid.
+pub fn verify_detached(data: &[u8], sig: &str, keys: &PublicKeys) -> bool {
+  let (name, sig) = match break_str(sig, ':') {
+    Some(x) => x,
+    None => return false,
+  };
+  let key = match keys.get(name) {
+    Some(k) => k,
+    None => return false,
+  };
+  match base64::decode(sig) {
+    Ok(sig) if sig.len() == SIGNATURE_LEN => key.verify(data, &sig),
+    _ => false,
+  }
+}
+
+/// The string that gets signed for a store path, which commits to its
+/// contents and references: `1;path;narHash;narSize;refs`.
+pub fn fingerprint<S: Store + ?Sized, I: PathInfo + ?Sized>(store: &S, info: &I) -> Result<String> {
+  let path = store.print_store_path(info.store_path());
+  let nar_size = info.nar_size().filter(|&n| n != 0).ok_or_else(|| {
+    anyhow!(
+      "cannot calculate fingerprint of path {} because its size is not known",
+      path
+    )
+  })?;
+  ensure!(
+    info.nar_hash().type_() == HashType::SHA256,
+    "cannot calculate fingerprint of path {} because its hash is not SHA-256",
+    path
+  );
+  Ok(format!(
+    "1;{};{};{};{}",
+    path,
+    info.nar_hash().encode_with_type(Encoding::Base32),
+    nar_size,
+    itertools::join(
+      info.references().iter().map(|r| store.print_store_path(r)),
+      ","
+    )
+  ))
+}
+
+/// Sign `info` with `key`, adding the signature to its existing ones.
+pub fn sign_path_info<S: Store + ?Sized>(
+  store: &S,
+  info: &mut ValidPathInfo,
+  key: &SecretKey,
+) -> Result<()> {
+  let sig = key.sign(fingerprint(store, info)?.as_bytes());
+  info.signatures.insert(sig);
+  Ok(())
+}
+
+/// Count the signatures on `info` that are valid according to `keys`.
+pub fn check_signatures<S: Store + ?Sized, I: PathInfo + ?Sized>(
+  store: &S,
+  info: &I,
+  keys: &PublicKeys,
+) -> Result<usize> {
+  if info.signatures().is_empty() {
+    return Ok(0);
+  }
+  let fingerprint = fingerprint(store, info)?;
+  Ok(
+    info
+      .signatures()
+      .iter()
+      .filter(|sig| verify_detached(fingerprint.as_bytes(), sig, keys))
+      .count(),
+  )
+}
+
+/// Whether `info` describes a content-addressed path, i.e. one whose store
+/// path can be recomputed from its `CA` field. Such paths don't need to be
+/// signed.
+pub fn is_content_addressed<S: Store + ?Sized, I: PathInfo + ?Sized>(
+  store: &S,
+  info: &I,
+) -> Result<bool> {
+  let ca = match info.content_addressed() {
+    Some(ca) if !ca.is_empty() => ca,
+    _ => return Ok(false),
+  };
+  let path = info.store_path();
+  let expected = if let Some(hash) = ca.strip_prefix("text:") {
+    store.make_text_path(
+      &path.name,
+      &Hash::decode(hash)?,
+      info.references().iter().cloned(),
+    )?
+  } else if let Some(hash) = ca.strip_prefix("fixed:") {
+    let (method, hash) = match hash.strip_prefix("r:") {
+      Some(h) => (FileIngestionMethod::Recursive, h),
+      None => (FileIngestionMethod::Flat, hash),
+    };
+    let hash = Hash::decode(hash)?;
+    // only recursive SHA-256 paths are allowed to have references
+    if !(method == FileIngestionMethod::Recursive && hash.type_() == HashType::SHA256)
+      && !info.references().is_empty()
+    {
+      return Ok(false);
+    }
+    let has_self_reference = info.references().contains(path);
+    store.make_fixed_output_path(
+      method,
+      &hash,
+      &path.name,
+      info.references().iter().filter(|r| *r != path).cloned(),
+      has_self_reference,
+    )?
+  } else {
+    bail!("unknown content address `{}'", ca)
+  };
+  Ok(&expected == path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::LocalBinaryCacheStore;
+
+  #[test]
+  fn test_sign_verify() -> Result<()> {
+    Settings::init_for_tests();
+    let dir = tempfile::tempdir()?;
+    let store = LocalBinaryCacheStore::new(
+      crate::store::binary_cache::LocalBinaryCache::new(dir.path())?,
+      "/nix/store".into(),
+    )?;
+
+    let dep = StorePath::from_base_name("02w9f4k4sf1q5hdf1fp6lq4r7dpmp0xq-glibc-2.31")?;
+    let path = StorePath::from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-hello-2.10")?;
+    let mut info = ValidPathInfo::new(path, Hash::hash_str("", HashType::SHA256));
+    info.nar_size = Some(120);
+    info.references.insert(dep);
+
+    assert_eq!(
+      fingerprint(&store, &info)?,
+      "1;/nix/store/7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-hello-2.10;\
+       sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73;120;\
+       /nix/store/02w9f4k4sf1q5hdf1fp6lq4r7dpmp0xq-glibc-2.31"
+    );
+
+    let key = SecretKey::generate("cache.example.org-1")?;
+    assert_eq!(
+      SecretKey::parse(&key.to_string())?.to_string(),
+      key.to_string()
+    );
+    let mut keys = PublicKeys::new();
+    keys.insert(
+      key.name.clone(),
+      PublicKey::parse(&key.to_public_key().to_string())?,
+    );
+
+    sign_path_info(&store, &mut info, &key)?;
+    assert_eq!(check_signatures(&store, &info, &keys)?, 1);
+
+    // signatures don't carry over to different contents
+    info.nar_size = Some(121);
+    assert_eq!(check_signatures(&store, &info, &keys)?, 0);
+
+    Ok(())
+  }
+}
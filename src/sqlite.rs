@@ -13,7 +13,7 @@ impl Sqlite {
     Ok(Self(conn))
   }
 
-  pub fn _set_is_cache(&self) -> Result<()> {
+  pub fn set_is_cache(&self) -> Result<()> {
     self.pragma_update(None, "synchronous", &"off")?;
     self.pragma_update(Some(DatabaseName::Main), "journal_mode", &"truncate")?;
     Ok(())
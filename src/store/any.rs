@@ -0,0 +1,220 @@
+use super::{
+  binary_cache::{self, BinaryCache},
+  BinaryCacheStore, CheckSigsFlag, DummyStore, FileIngestionMethod, LocalStore, MemoryStore,
+  RemoteStore, RepairFlag,
+};
+use crate::{archive::PathFilter, prelude::*};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  ffi::OsStr,
+};
+use unix::unistd::{access, AccessFlags};
+
+/// A store whose backend is picked at runtime, since [`Store`] can't be used
+/// as a trait object.
+#[derive(Debug)]
+pub enum AnyStore {
+  Local(LocalStore),
+  Remote(RemoteStore),
+  BinaryCache(BinaryCacheStore<Box<dyn BinaryCache>>),
+  Dummy(DummyStore),
+  Memory(MemoryStore),
+}
+
+/// Split a store URI into its base and its `?name=value&...` parameters.
+pub fn parse_store_uri(uri: &str) -> Result<(&str, BTreeMap<&str, &str>)> {
+  let (base, query) = break_str(uri, '?').unwrap_or((uri, ""));
+  let params = query
+    .split('&')
+    .filter(|x| !x.is_empty())
+    .map(|param| {
+      break_str(param, '=').ok_or_else(|| anyhow!("invalid parameter `{}' in store URI", param))
+    })
+    .collect::<Result<_>>()?;
+  Ok((base, params))
+}
+
+/// Open the store named by the `store` setting.
+pub fn open_store() -> Result<AnyStore> {
+  open_store_uri(&settings().store_uri)
+}
+
+/// Open the store named by `uri`, which is one of
+///
+/// * `auto`: the local store if we can write to it, otherwise the daemon
+/// * `local`: the local store, opened directly; with `?root=DIR` or given as
+///   an absolute path `DIR`, its files live under `DIR`
+/// * `daemon` or `unix://PATH`: a store served by the Nix daemon
+/// * `file://PATH`, `http://URL` or `https://URL`: a binary cache
+/// * `dummy://`: a store that contains nothing
+pub fn open_store_uri(uri: &str) -> Result<AnyStore> {
+  let (base, mut params) = parse_store_uri(uri)?;
+
+  // binary caches have parameters of their own
+  if base.starts_with("file://") || base.starts_with("http://") || base.starts_with("https://") {
+    return Ok(AnyStore::BinaryCache(binary_cache::open(uri)?));
+  }
+
+  let settings = settings();
+  let socket = &settings.paths.nix_daemon_socket_file;
+  let store = match base {
+    "auto" => {
+      if access(&settings.paths.nix_state_dir, AccessFlags::W_OK).is_err() && socket.exists() {
+        AnyStore::Remote(RemoteStore::open(socket)?)
+      } else {
+        AnyStore::Local(LocalStore::open()?)
+      }
+    }
+    "local" => match params.remove("root") {
+      Some(root) => AnyStore::Local(LocalStore::open_chroot(root)?),
+      None => AnyStore::Local(LocalStore::open()?),
+    },
+    "daemon" => AnyStore::Remote(RemoteStore::open(socket)?),
+    "dummy://" => AnyStore::Dummy(DummyStore),
+    _ if base.starts_with('/') => AnyStore::Local(LocalStore::open_chroot(base)?),
+    _ => match base.strip_prefix("unix://") {
+      Some(path) => AnyStore::Remote(RemoteStore::open(path)?),
+      None => bail!("don't know how to open Nix store `{}'", uri),
+    },
+  };
+
+  for name in params.keys() {
+    warn!("ignoring unknown parameter `{}' for store `{}'", name, base);
+  }
+
+  Ok(store)
+}
+
+macro_rules! dispatch {
+  ($self:ident, $store:ident => $e:expr) => {
+    match $self {
+      AnyStore::Local($store) => $e,
+      AnyStore::Remote($store) => $e,
+      AnyStore::BinaryCache($store) => $e,
+      AnyStore::Dummy($store) => $e,
+      AnyStore::Memory($store) => $e,
+    }
+  };
+}
+
+impl Store for AnyStore {
+  fn store_path(&self) -> Cow<OsStr> {
+    dispatch!(self, s => s.store_path())
+  }
+
+  fn to_real_path<P: Borrow<StorePath>>(&self, path: P) -> Result<PathBuf> {
+    dispatch!(self, s => s.to_real_path(path))
+  }
+
+  fn log_dir(&self) -> Cow<Path> {
+    dispatch!(self, s => s.log_dir())
+  }
+
+  fn read_derivation<P: Borrow<StorePath>>(&self, path: P) -> Result<Derivation> {
+    dispatch!(self, s => s.read_derivation(path))
+  }
+
+  fn get_path_info<P: Borrow<StorePath>>(&self, path: P) -> Result<Option<Rc<dyn PathInfo>>> {
+    dispatch!(self, s => s.get_path_info(path))
+  }
+
+  fn is_valid_path(&self, path: &StorePath) -> Result<bool> {
+    dispatch!(self, s => s.is_valid_path(path))
+  }
+
+  fn register_valid_paths<I: IntoIterator<Item = ValidPathInfo>>(
+    &self,
+    path_infos: I,
+  ) -> Result<()> {
+    dispatch!(self, s => s.register_valid_paths(path_infos))
+  }
+
+  fn add_temp_root(&self, path: &StorePath) -> Result<()> {
+    dispatch!(self, s => s.add_temp_root(path))
+  }
+
+  fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    dispatch!(self, s => s.query_referrers(path))
+  }
+
+  fn query_valid_derivers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    dispatch!(self, s => s.query_valid_derivers(path))
+  }
+
+  fn query_derivation_outputs(&self, path: &StorePath) -> Result<BTreeMap<String, StorePath>> {
+    dispatch!(self, s => s.query_derivation_outputs(path))
+  }
+
+  fn add_text_to_store<I: IntoIterator<Item = StorePath>>(
+    &self,
+    name: &str,
+    contents: &str,
+    references: I,
+    repair: RepairFlag,
+  ) -> Result<StorePath> {
+    dispatch!(self, s => s.add_text_to_store(name, contents, references, repair))
+  }
+
+  fn add_to_store_from_source<I: PathInfo, R: Read>(
+    &self,
+    info: I,
+    source: R,
+    repair: RepairFlag,
+    check_sigs: CheckSigsFlag,
+  ) -> Result<()> {
+    dispatch!(self, s => s.add_to_store_from_source(info, source, repair, check_sigs))
+  }
+
+  fn add_to_store_from_path(
+    &self,
+    name: &str,
+    path: &Path,
+    ingest_method: FileIngestionMethod,
+    hash_type: HashType,
+    filter: &PathFilter,
+    repair: RepairFlag,
+  ) -> Result<StorePath> {
+    dispatch!(self, s => {
+      s.add_to_store_from_path(name, path, ingest_method, hash_type, filter, repair)
+    })
+  }
+
+  fn nar_from_path<W: Write>(&self, path: &StorePath, sink: W) -> Result<()> {
+    dispatch!(self, s => s.nar_from_path(path, sink))
+  }
+
+  fn add_signatures(&self, path: &StorePath, sigs: &BTreeSet<String>) -> Result<()> {
+    dispatch!(self, s => s.add_signatures(path, sigs))
+  }
+
+  fn sign_path_info(&self, info: &mut ValidPathInfo) -> Result<()> {
+    dispatch!(self, s => s.sign_path_info(info))
+  }
+
+  fn build_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
+    dispatch!(self, s => s.build_paths(paths))
+  }
+
+  fn check_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
+    dispatch!(self, s => s.check_paths(paths))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_store_uri() -> Result<()> {
+    assert_eq!(parse_store_uri("daemon")?, ("daemon", BTreeMap::new()));
+    assert_eq!(
+      parse_store_uri("file:///cache?compression=none&secret-key=/etc/key")?,
+      (
+        "file:///cache",
+        maplit::btreemap! { "compression" => "none", "secret-key" => "/etc/key" }
+      )
+    );
+    assert!(parse_store_uri("local?root").is_err());
+    Ok(())
+  }
+}
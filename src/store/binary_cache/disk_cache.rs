@@ -0,0 +1,132 @@
+//! A cache of `.narinfo` lookups shared between processes, so that asking a
+//! remote binary cache about the same paths again doesn't cost a round trip.
+
+use crate::{prelude::*, sqlite::Sqlite};
+use parking_lot::Mutex;
+use rusqlite::{params, OptionalExtension};
+use std::time::SystemTime;
+
+static SCHEMA: &str = "create table if not exists NarInfos (
+    cache     text not null,
+    hashPart  text not null,
+    narInfo   text, -- null if the cache doesn't have the path
+    timestamp integer not null,
+    primary key (cache, hashPart)
+);";
+
+lazy_static! {
+  static ref DISK_CACHE: Option<NarInfoDiskCache> = {
+    let db_file = dirs_next::cache_dir()
+      .unwrap_or_else(std::env::temp_dir)
+      .join("nix")
+      .join("narinfo-cache-v1.sqlite");
+    NarInfoDiskCache::open(&db_file)
+      .map_err(|e| warn!("unable to open NAR info cache: {:#}", e))
+      .ok()
+  };
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Outcome {
+  /// Never looked up, or the answer has expired.
+  Unknown,
+  /// The binary cache doesn't have the path.
+  Invalid,
+  /// The contents of the `.narinfo` file.
+  Valid(String),
+}
+
+#[derive(Debug)]
+pub struct NarInfoDiskCache(Mutex<Sqlite>);
+
+impl NarInfoDiskCache {
+  /// The cache in the user's cache directory, unless it couldn't be opened.
+  pub fn get() -> Option<&'static Self> {
+    DISK_CACHE.as_ref()
+  }
+
+  pub fn open(db_file: &Path) -> Result<Self> {
+    if let Some(parent) = db_file.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let db = Sqlite::open(db_file)?;
+    db.set_is_cache()?;
+    db.busy_timeout(Duration::from_secs(60))?;
+    db.execute_batch(SCHEMA)?;
+    Ok(Self(Mutex::new(db)))
+  }
+
+  pub fn lookup(&self, uri: &str, hash_part: &str) -> Result<Outcome> {
+    let row = self
+      .0
+      .lock()
+      .query_row(
+        "select narInfo, timestamp from NarInfos where cache = ? and hashPart = ?",
+        params![uri, hash_part],
+        |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, i64>(1)?)),
+      )
+      .optional()?;
+    let (nar_info, timestamp) = match row {
+      Some(x) => x,
+      None => return Ok(Outcome::Unknown),
+    };
+
+    let ttl = if nar_info.is_some() {
+      settings().ttl_positive_nar_info_cache
+    } else {
+      settings().ttl_negative_nar_info_cache
+    };
+    if SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp as u64) + ttl < SystemTime::now() {
+      return Ok(Outcome::Unknown);
+    }
+
+    Ok(nar_info.map_or(Outcome::Invalid, Outcome::Valid))
+  }
+
+  /// Remember the result of looking up `hash_part` in the cache at `uri`.
+  pub fn upsert(&self, uri: &str, hash_part: &str, nar_info: Option<&str>) -> Result<()> {
+    let now = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)?
+      .as_secs() as i64;
+    self.0.lock().execute(
+      "insert or replace into NarInfos (cache, hashPart, narInfo, timestamp) values (?, ?, ?, ?)",
+      params![uri, hash_part, nar_info, now],
+    )?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_disk_cache() -> Result<()> {
+    Settings::init_for_tests();
+    let dir = tempfile::tempdir()?;
+    let cache = NarInfoDiskCache::open(&dir.path().join("cache.sqlite"))?;
+    let uri = "https://cache.example.org";
+
+    assert_eq!(cache.lookup(uri, "foo")?, Outcome::Unknown);
+    cache.upsert(uri, "foo", Some("StorePath: x\n"))?;
+    cache.upsert(uri, "bar", None)?;
+    assert_eq!(
+      cache.lookup(uri, "foo")?,
+      Outcome::Valid("StorePath: x\n".into())
+    );
+    assert_eq!(cache.lookup(uri, "bar")?, Outcome::Invalid);
+    assert_eq!(
+      cache.lookup("https://other.example.org", "foo")?,
+      Outcome::Unknown
+    );
+
+    // answers expire
+    cache
+      .0
+      .lock()
+      .execute("update NarInfos set timestamp = 0", rusqlite::NO_PARAMS)?;
+    assert_eq!(cache.lookup(uri, "foo")?, Outcome::Unknown);
+    assert_eq!(cache.lookup(uri, "bar")?, Outcome::Unknown);
+    Ok(())
+  }
+}
@@ -0,0 +1,140 @@
+use super::*;
+use std::os::unix::fs::PermissionsExt;
+use unix::sys::stat::{umask, Mode};
+
+/// A binary cache in a local directory, e.g. one that gets synced to a web
+/// server or shared over NFS.
+#[derive(Debug)]
+pub struct LocalBinaryCache {
+  uri: String,
+  root: PathBuf,
+}
+
+pub type LocalBinaryCacheStore = BinaryCacheStore<LocalBinaryCache>;
+
+impl LocalBinaryCache {
+  pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+    let root = root.as_ref();
+    fs::create_dir_all(root.join("nar"))
+      .with_context(|| format!("while creating binary cache in `{}'", root.display()))?;
+    Ok(Self {
+      uri: format!("file://{}", root.display()),
+      root: root.into(),
+    })
+  }
+}
+
+impl BinaryCache for LocalBinaryCache {
+  fn uri(&self) -> &str {
+    &self.uri
+  }
+
+  fn get_file(&self, path: &str) -> Result<Option<Box<dyn Read>>> {
+    match fs::File::open(self.root.join(path)) {
+      Ok(f) => Ok(Some(Box::new(f))),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn file_exists(&self, path: &str) -> Result<bool> {
+    Ok(self.root.join(path).exists())
+  }
+
+  fn upsert_file(&self, path: &str, data: &mut dyn Read, _mime_type: &str) -> Result<()> {
+    let dest = self.root.join(path);
+    let dir = dest.parent().unwrap_or(&self.root);
+    fs::create_dir_all(dir)?;
+    // write to a temporary file first so readers never see partial files
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    io::copy(data, &mut tmp)?;
+    // temporary files are private, but the cache is meant to be shared
+    let mask = umask(Mode::empty());
+    umask(mask);
+    tmp
+      .as_file()
+      .set_permissions(fs::Permissions::from_mode(0o644 & !(mask.bits() as u32)))?;
+    tmp.persist(&dest)?;
+    Ok(())
+  }
+}
+
+impl LocalBinaryCacheStore {
+  pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+    Self::new(
+      LocalBinaryCache::new(root)?,
+      settings().paths.nix_store.clone(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive;
+
+  #[test]
+  fn test_roundtrip() -> Result<()> {
+    Settings::init_for_tests();
+    let dir = tempfile::tempdir()?;
+    let dep = StorePath::from_base_name("02w9f4k4sf1q5hdf1fp6lq4r7dpmp0xq-glibc-2.31")?;
+    let path = StorePath::from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-hello-2.10")?;
+
+    for method in &[Compression::None, Compression::Xz, Compression::Zstd] {
+      let store = BinaryCacheStore::new(
+        LocalBinaryCache::new(dir.path().join(method.to_string()))?,
+        "/nix/store".into(),
+      )?
+      .with_compression(*method);
+
+      let dep_contents = b"libc.so.6";
+      let dep_nar = archive::dump_to_bytes(dep_contents.len(), &dep_contents[..])?;
+      let dep_info = ValidPathInfo::new(dep.clone(), Hash::hash_bytes(&dep_nar, HashType::SHA256));
+      store.add_to_store_from_source(
+        dep_info,
+        dep_nar.as_slice(),
+        RepairFlag::NoRepair,
+        CheckSigsFlag::NoCheckSigs,
+      )?;
+
+      // only the reference that actually occurs in the NAR should be recorded
+      let contents = format!("#!/nix/store/{}/bin/sh", dep);
+      let nar = archive::dump_to_bytes(contents.len(), contents.as_bytes())?;
+      let mut info = ValidPathInfo::new(path.clone(), Hash::hash_bytes(&nar, HashType::SHA256));
+      info.references.insert(dep.clone());
+      info.references.insert(path.clone());
+      store.add_to_store_from_source(
+        info,
+        nar.as_slice(),
+        RepairFlag::NoRepair,
+        CheckSigsFlag::NoCheckSigs,
+      )?;
+
+      // read it back through a fresh store so nothing comes from memory
+      let store = BinaryCacheStore::new(
+        LocalBinaryCache::new(dir.path().join(method.to_string()))?,
+        "/nix/store".into(),
+      )?;
+      let info = store.get_path_info(&path)?.expect("path should be valid");
+      assert_eq!(info.references(), &maplit::btreeset! { dep.clone() });
+      assert_eq!(info.nar_size(), Some(nar.len() as u64));
+
+      let mut fetched = vec![];
+      store.nar_from_path(&path, &mut fetched)?;
+      assert_eq!(fetched, nar);
+
+      let narinfo = dir
+        .path()
+        .join(method.to_string())
+        .join(format!("{}.narinfo", path.hash));
+      let mask = umask(Mode::empty());
+      umask(mask);
+      assert_eq!(
+        fs::metadata(narinfo)?.permissions().mode() & 0o777,
+        0o644 & !(mask.bits() as u32)
+      );
+    }
+
+    Ok(())
+  }
+}
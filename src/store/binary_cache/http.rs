@@ -0,0 +1,154 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct HttpBinaryCache {
+  uri: String,
+}
+
+pub type HttpBinaryCacheStore = BinaryCacheStore<HttpBinaryCache>;
+
+impl HttpBinaryCache {
+  pub fn new(uri: &str) -> Self {
+    Self {
+      uri: uri.trim_end_matches('/').to_string(),
+    }
+  }
+}
+
+impl BinaryCache for HttpBinaryCache {
+  fn uri(&self) -> &str {
+    &self.uri
+  }
+
+  fn get_file(&self, path: &str) -> Result<Option<Box<dyn Read>>> {
+    let url = format!("{}/{}", self.uri, path);
+    debug!("downloading `{}'", url);
+
+    let response = ureq::get(&url).call();
+    if let Some(e) = response.synthetic_error() {
+      bail!("unable to download `{}': {}", url, e);
+    }
+
+    match response.status() {
+      // S3 returns 403 for files that don't exist
+      403 | 404 => Ok(None),
+      _ if response.ok() => Ok(Some(Box::new(response.into_reader()))),
+      status => bail!(
+        "unable to download `{}': HTTP error {} ({})",
+        url,
+        status,
+        response.status_text()
+      ),
+    }
+  }
+}
+
+impl HttpBinaryCacheStore {
+  pub fn open(uri: &str) -> Result<Self> {
+    Self::new(
+      HttpBinaryCache::new(uri),
+      settings().paths.nix_store.clone(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{archive, util::compression::Compression};
+  use std::{
+    io::{BufRead, BufReader},
+    net::TcpListener,
+    thread,
+  };
+
+  /// Serve `files` over HTTP on an ephemeral port and return the base URL.
+  fn serve(files: HashMap<String, Vec<u8>>) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}", listener.local_addr()?);
+
+    thread::spawn(move || {
+      for mut stream in listener.incoming().filter_map(|s| s.ok()) {
+        let mut request = String::new();
+        {
+          let mut reader = BufReader::new(&stream);
+          let mut line = String::new();
+          while reader.read_line(&mut line).map_or(false, |n| n > 2) {
+            if request.is_empty() {
+              request = line.clone();
+            }
+            line.clear();
+          }
+        }
+
+        let path = request
+          .split_whitespace()
+          .nth(1)
+          .unwrap_or("/")
+          .trim_start_matches('/');
+        let _ = match files.get(path) {
+          Some(body) => write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+          )
+          .and_then(|_| stream.write_all(body)),
+          None => stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+        };
+      }
+    });
+
+    Ok(url)
+  }
+
+  #[test]
+  fn test_fetch_nar() -> Result<()> {
+    Settings::init_for_tests();
+    let path = StorePath::from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-hello-2.10")?;
+    let dep = StorePath::from_base_name("02w9f4k4sf1q5hdf1fp6lq4r7dpmp0xq-glibc-2.31")?;
+
+    let contents = b"Hello, world!\n";
+    let nar = archive::dump_to_bytes(contents.len(), &contents[..])?;
+    let compressed = Compression::Xz.compress(nar.as_slice(), vec![])?;
+    let file_hash = Hash::hash_bytes(&compressed, HashType::SHA256);
+    let nar_hash = Hash::hash_bytes(&nar, HashType::SHA256);
+    let nar_url = format!("nar/{}.nar.xz", file_hash.encode(Encoding::Base32));
+
+    let nar_info = format!(
+      "StorePath: /nix/store/{}\nURL: {}\nCompression: xz\nFileHash: {}\nFileSize: {}\nNarHash: \
+       {}\nNarSize: {}\nReferences: {}\nSig: cache.example.org-1:AAAA\n",
+      path,
+      nar_url,
+      file_hash.encode_with_type(Encoding::Base32),
+      compressed.len(),
+      nar_hash.encode_with_type(Encoding::Base32),
+      nar.len(),
+      dep
+    );
+
+    let url = serve(maplit::hashmap! {
+      "nix-cache-info".into() => b"StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 40\n".to_vec(),
+      format!("{}.narinfo", path.hash) => nar_info.into_bytes(),
+      nar_url => compressed,
+    })?;
+
+    let store = BinaryCacheStore::new(HttpBinaryCache::new(&url), "/nix/store".into())?;
+    assert_eq!(store.priority(), 40);
+    assert!(store.want_mass_query());
+
+    let info = store.get_path_info(&path)?.expect("path should be valid");
+    assert_eq!(info.nar_hash(), &nar_hash);
+    assert_eq!(info.nar_size(), Some(nar.len() as u64));
+    assert_eq!(info.references(), &maplit::btreeset! { dep });
+
+    let mut fetched = vec![];
+    store.nar_from_path(&path, &mut fetched)?;
+    assert_eq!(fetched, nar);
+
+    let missing = StorePath::from_base_name("0000000000000000000000000000000a-missing")?;
+    assert!(!store.is_valid_path(&missing)?);
+
+    Ok(())
+  }
+}
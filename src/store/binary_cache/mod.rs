@@ -0,0 +1,389 @@
+//! Stores backed by a binary cache, i.e. a flat collection of `.narinfo`
+//! files and compressed NARs such as the one served by `cache.nixos.org`.
+
+use super::{CheckSigsFlag, FileIngestionMethod, RepairFlag};
+use crate::{
+  archive::{PathFilter, RefsScanner},
+  nar_info::NarInfo,
+  prelude::*,
+  signing::{self, SecretKey},
+  util::compression::Compression,
+};
+use parking_lot::Mutex;
+use std::{collections::HashMap, ffi::OsStr, fmt::Debug, io::SeekFrom};
+use tee_readwrite::TeeWriter;
+
+mod disk_cache;
+mod file;
+mod http;
+
+pub use disk_cache::NarInfoDiskCache;
+pub use file::*;
+pub use http::*;
+
+static CACHE_INFO_FILE: &str = "nix-cache-info";
+
+/// The file operations a binary cache backend has to provide. Paths are
+/// relative to the root of the cache.
+pub trait BinaryCache: Send + Sync + Debug {
+  fn uri(&self) -> &str;
+
+  /// Returns `None` if the file doesn't exist.
+  fn get_file(&self, path: &str) -> Result<Option<Box<dyn Read>>>;
+
+  fn file_exists(&self, path: &str) -> Result<bool> {
+    Ok(self.get_file(path)?.is_some())
+  }
+
+  #[allow(unused_variables)]
+  fn upsert_file(&self, path: &str, data: &mut dyn Read, mime_type: &str) -> Result<()> {
+    bail!("binary cache `{}' is read-only", self.uri())
+  }
+}
+
+impl BinaryCache for Box<dyn BinaryCache> {
+  fn uri(&self) -> &str {
+    (**self).uri()
+  }
+
+  fn get_file(&self, path: &str) -> Result<Option<Box<dyn Read>>> {
+    (**self).get_file(path)
+  }
+
+  fn file_exists(&self, path: &str) -> Result<bool> {
+    (**self).file_exists(path)
+  }
+
+  fn upsert_file(&self, path: &str, data: &mut dyn Read, mime_type: &str) -> Result<()> {
+    (**self).upsert_file(path, data, mime_type)
+  }
+}
+
+/// Open the binary cache at `uri`, choosing a backend based on the scheme.
+/// The compression method for uploaded NARs can be given as a
+/// `?compression=` parameter, and a key to sign them with as `?secret-key=`.
+pub fn open(uri: &str) -> Result<BinaryCacheStore<Box<dyn BinaryCache>>> {
+  let (uri, params) = super::parse_store_uri(uri)?;
+
+  let remote = uri.starts_with("http://") || uri.starts_with("https://");
+  let cache: Box<dyn BinaryCache> = if remote {
+    Box::new(HttpBinaryCache::new(uri))
+  } else if let Some(path) = uri.strip_prefix("file://") {
+    Box::new(LocalBinaryCache::new(path)?)
+  } else {
+    bail!("don't know how to open binary cache `{}'", uri)
+  };
+
+  let mut store = BinaryCacheStore::new(cache, settings().paths.nix_store.clone())?;
+  // local caches are cheap enough to query directly
+  if remote {
+    store = store.with_disk_cache();
+  }
+  for (name, value) in params {
+    match name {
+      "compression" => store = store.with_compression(value.parse()?),
+      "secret-key" => store = store.with_secret_key(SecretKey::read(value)?),
+      _ => warn!("ignoring unknown binary cache parameter `{}'", name),
+    }
+  }
+  Ok(store)
+}
+
+#[derive(Debug)]
+pub struct BinaryCacheStore<C> {
+  cache: C,
+  store_dir: PathBuf,
+  want_mass_query: bool,
+  priority: u32,
+  compression: Compression,
+  secret_key: Option<SecretKey>,
+  nar_info_cache: Mutex<HashMap<StorePath, Option<NarInfo>>>,
+  disk_cache: Option<&'static NarInfoDiskCache>,
+}
+
+impl<C: BinaryCache> BinaryCacheStore<C> {
+  pub fn new(cache: C, store_dir: PathBuf) -> Result<Self> {
+    let mut this = Self {
+      cache,
+      store_dir,
+      want_mass_query: false,
+      priority: 50,
+      compression: Compression::Xz,
+      secret_key: None,
+      nar_info_cache: Default::default(),
+      disk_cache: None,
+    };
+
+    if let Some(mut file) = this.cache.get_file(CACHE_INFO_FILE)? {
+      let mut contents = String::new();
+      file.read_to_string(&mut contents)?;
+      for line in contents.lines() {
+        if let Some((name, value)) = break_str(line, ':') {
+          let value = value.trim();
+          match name {
+            "StoreDir" => ensure!(
+              Path::new(value) == this.store_dir,
+              "binary cache `{}' is for Nix stores with prefix `{}', not `{}'",
+              this.uri(),
+              value,
+              this.store_dir.display()
+            ),
+            "WantMassQuery" => this.want_mass_query = value == "1",
+            "Priority" => this.priority = value.parse()?,
+            _ => {}
+          }
+        }
+      }
+    } else {
+      let contents = format!("StoreDir: {}\n", this.store_dir.display());
+      this.cache.upsert_file(
+        CACHE_INFO_FILE,
+        &mut contents.as_bytes(),
+        "text/x-nix-cache-info",
+      )?;
+    }
+
+    Ok(this)
+  }
+
+  /// Set the compression method used for NARs added to this cache.
+  pub fn with_compression(mut self, compression: Compression) -> Self {
+    self.compression = compression;
+    self
+  }
+
+  /// Sign the paths added to this cache with `key`.
+  pub fn with_secret_key(mut self, key: SecretKey) -> Self {
+    self.secret_key = Some(key);
+    self
+  }
+
+  /// Remember `.narinfo` lookups in the on-disk cache shared by all
+  /// processes, if it's available.
+  pub fn with_disk_cache(mut self) -> Self {
+    self.disk_cache = NarInfoDiskCache::get();
+    self
+  }
+
+  pub fn uri(&self) -> &str {
+    self.cache.uri()
+  }
+
+  /// Lower values are preferred when substituting.
+  pub fn priority(&self) -> u32 {
+    self.priority
+  }
+
+  pub fn want_mass_query(&self) -> bool {
+    self.want_mass_query
+  }
+
+  fn nar_info_file(path: &StorePath) -> String {
+    format!("{}.narinfo", path.hash)
+  }
+
+  pub fn query_nar_info(&self, path: &StorePath) -> Result<Option<NarInfo>> {
+    if let Some(info) = self.nar_info_cache.lock().get(path) {
+      return Ok(info.clone());
+    }
+
+    let file = Self::nar_info_file(path);
+    let whence = format!("{}/{}", self.uri(), file);
+    let hash_part = path.hash.to_string();
+
+    let cached = match self.disk_cache {
+      Some(disk_cache) => disk_cache.lookup(self.uri(), &hash_part)?,
+      None => disk_cache::Outcome::Unknown,
+    };
+    let contents = match cached {
+      disk_cache::Outcome::Valid(contents) => Some(contents),
+      disk_cache::Outcome::Invalid => None,
+      disk_cache::Outcome::Unknown => {
+        let contents = match self.cache.get_file(&file)? {
+          Some(mut reader) => {
+            let mut contents = String::new();
+            reader.read_to_string(&mut contents)?;
+            Some(contents)
+          }
+          None => None,
+        };
+        if let Some(disk_cache) = self.disk_cache {
+          disk_cache.upsert(self.uri(), &hash_part, contents.as_deref())?;
+        }
+        contents
+      }
+    };
+
+    let info = match contents {
+      Some(contents) => {
+        let info = NarInfo::parse(self, &contents, &whence)?;
+        ensure!(
+          &info.path_info.store_path == path,
+          "NAR info file `{}' does not describe path {}",
+          whence,
+          self.print_store_path(path)
+        );
+        Some(info)
+      }
+      None => None,
+    };
+
+    self
+      .nar_info_cache
+      .lock()
+      .insert(path.clone(), info.clone());
+
+    Ok(info)
+  }
+
+  /// Fetch the NAR described by `info`, decompressing it on the fly.
+  pub fn nar_reader(&self, info: &NarInfo) -> Result<Box<dyn Read>> {
+    let reader = self.cache.get_file(&info.url)?.ok_or_else(|| {
+      anyhow!(
+        "file `{}' does not exist in binary cache `{}'",
+        info.url,
+        self.uri()
+      )
+    })?;
+    Ok(info.compression.decompress(reader)?)
+  }
+}
+
+impl<C: BinaryCache> Store for BinaryCacheStore<C> {
+  fn store_path(&self) -> Cow<OsStr> {
+    Cow::Borrowed(self.store_dir.as_os_str())
+  }
+
+  fn get_path_info<P: Borrow<StorePath>>(&self, path: P) -> Result<Option<Rc<dyn PathInfo>>> {
+    Ok(
+      self
+        .query_nar_info(path.borrow())?
+        .map(|x| Rc::new(x) as Rc<dyn PathInfo>),
+    )
+  }
+
+  fn register_valid_paths<I: IntoIterator<Item = ValidPathInfo>>(
+    &self,
+    _path_infos: I,
+  ) -> Result<()> {
+    bail!("cannot register paths in binary cache `{}'", self.uri())
+  }
+
+  fn add_to_store_from_source<I: PathInfo, R: Read>(
+    &self,
+    info: I,
+    mut source: R,
+    repair: RepairFlag,
+    _check_sigs: CheckSigsFlag,
+  ) -> Result<()> {
+    let path = info.store_path();
+    if !repair.repair() && self.is_valid_path(path)? {
+      return Ok(());
+    }
+
+    // spool the NAR to disk, checking its hash and looking for references as we go
+    let mut nar = tempfile::tempfile()?;
+    let mut nar_hash = crate::hash::Sink::new(info.nar_hash().type_());
+    let mut scanner = RefsScanner::new(info.references().iter().cloned());
+    io::copy(
+      &mut source,
+      &mut TeeWriter::new(&mut nar, TeeWriter::new(&mut nar_hash, &mut scanner)),
+    )?;
+    let (nar_hash, nar_size) = nar_hash.finish();
+    ensure!(
+      &nar_hash == info.nar_hash(),
+      "hash mismatch importing path {};\n  specified: {}\n  got:       {}",
+      self.print_store_path(path),
+      info.nar_hash().encode_with_type(Encoding::Base32),
+      nar_hash.encode_with_type(Encoding::Base32)
+    );
+
+    let references = scanner.finish();
+    // signatures cover the references, so they can't be swapped for the ones
+    // that were found
+    ensure!(
+      info.signatures().is_empty() || &references == info.references(),
+      "cannot add {} to binary cache `{}' because its signed references don't match the ones \
+       in its NAR",
+      self.print_store_path(path),
+      self.uri()
+    );
+    for r in &references {
+      ensure!(
+        r == path || self.is_valid_path(r)?,
+        "cannot add {} to binary cache `{}' because its reference {} is not valid",
+        self.print_store_path(path),
+        self.uri(),
+        self.print_store_path(r)
+      );
+    }
+
+    nar.seek(SeekFrom::Start(0))?;
+    let mut compressed = tempfile::tempfile()?;
+    let mut file_hash = crate::hash::Sink::new(HashType::SHA256);
+    self
+      .compression
+      .compress(&mut nar, TeeWriter::new(&mut compressed, &mut file_hash))?;
+    let (file_hash, file_size) = file_hash.finish();
+
+    let url = format!(
+      "nar/{}.nar{}",
+      file_hash.encode(Encoding::Base32),
+      self.compression.extension()
+    );
+    if repair.repair() || !self.cache.file_exists(&url)? {
+      compressed.seek(SeekFrom::Start(0))?;
+      self
+        .cache
+        .upsert_file(&url, &mut compressed, "application/x-nix-nar")?;
+    }
+
+    let mut path_info = info.to_valid_path_info();
+    path_info.nar_size = Some(nar_size as u64);
+    path_info.references = references;
+    if let Some(key) = &self.secret_key {
+      signing::sign_path_info(self, &mut path_info, key)?;
+    }
+
+    let mut nar_info = NarInfo::new(path_info, url, self.compression);
+    nar_info.file_hash = Some(file_hash);
+    nar_info.file_size = Some(file_size as u64);
+
+    let contents = nar_info.unparse(self);
+    self.cache.upsert_file(
+      &Self::nar_info_file(path),
+      &mut contents.as_bytes(),
+      "text/x-nix-narinfo",
+    )?;
+    if let Some(disk_cache) = self.disk_cache {
+      disk_cache.upsert(self.uri(), &path.hash.to_string(), Some(&contents))?;
+    }
+
+    self
+      .nar_info_cache
+      .lock()
+      .insert(path.clone(), Some(nar_info));
+
+    Ok(())
+  }
+
+  fn add_to_store_from_path(
+    &self,
+    name: &str,
+    _path: &Path,
+    _ingest_method: FileIngestionMethod,
+    _hash_type: HashType,
+    _filter: &PathFilter,
+    _repair: RepairFlag,
+  ) -> Result<StorePath> {
+    bail!("cannot add `{}' to binary cache `{}'", name, self.uri())
+  }
+
+  fn nar_from_path<W: Write>(&self, path: &StorePath, mut sink: W) -> Result<()> {
+    let info = self
+      .query_nar_info(path)?
+      .ok_or_else(|| anyhow!("path {} is not valid", self.print_store_path(path)))?;
+    io::copy(&mut self.nar_reader(&info)?, &mut sink)?;
+    Ok(())
+  }
+}
@@ -0,0 +1,44 @@
+use super::{CheckSigsFlag, FileIngestionMethod, RepairFlag};
+use crate::{archive::PathFilter, prelude::*};
+use std::ffi::OsStr;
+
+/// A store that contains nothing and can't be added to, for evaluating
+/// expressions without touching a real store.
+#[derive(Debug, Default)]
+pub struct DummyStore;
+
+impl Store for DummyStore {
+  fn store_path(&self) -> Cow<OsStr> {
+    Cow::Owned(settings().paths.nix_store.clone().into_os_string())
+  }
+
+  fn get_path_info<P: Borrow<StorePath>>(&self, _: P) -> Result<Option<Rc<dyn PathInfo>>> {
+    Ok(None)
+  }
+
+  fn register_valid_paths<I: IntoIterator<Item = ValidPathInfo>>(&self, _: I) -> Result<()> {
+    bail!("the dummy store doesn't support adding paths")
+  }
+
+  fn add_to_store_from_source<I: PathInfo, R: Read>(
+    &self,
+    _: I,
+    _: R,
+    _: RepairFlag,
+    _: CheckSigsFlag,
+  ) -> Result<()> {
+    bail!("the dummy store doesn't support adding paths")
+  }
+
+  fn add_to_store_from_path(
+    &self,
+    _: &str,
+    _: &Path,
+    _: FileIngestionMethod,
+    _: HashType,
+    _: &PathFilter,
+    _: RepairFlag,
+  ) -> Result<StorePath> {
+    bail!("the dummy store doesn't support adding paths")
+  }
+}
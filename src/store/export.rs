@@ -0,0 +1,199 @@
+//! The format used by `nix-store --export` and `--import`: a sequence of
+//! NARs, each followed by the path's metadata.
+
+use super::{topo_sort, CheckSigsFlag, RepairFlag};
+use crate::{archive, prelude::*};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  io::SeekFrom,
+};
+use tee_readwrite::TeeWriter;
+
+const EXPORT_MAGIC: usize = 0x4558_494e;
+
+/// Write `paths` to `sink` in export format, references first. The closure
+/// of `paths` is not included.
+pub fn export_paths<S: Store + ?Sized, W: Write>(
+  store: &S,
+  paths: &BTreeSet<StorePath>,
+  sink: W,
+) -> Result<()> {
+  let mut sink = archive::Sink::new(sink);
+  for path in store.topo_sort_paths(paths)?.iter().rev() {
+    sink.write_usize(1)?;
+    export_path(store, path, &mut sink)?;
+  }
+  sink.write_usize(0)?;
+  Ok(())
+}
+
+fn export_path<S: Store + ?Sized, W: Write>(
+  store: &S,
+  path: &StorePath,
+  sink: &mut archive::Sink<W>,
+) -> Result<()> {
+  let info = store
+    .get_path_info(path)?
+    .ok_or_else(|| anyhow!("path {} is not valid", store.print_store_path(path)))?;
+
+  let mut hasher = crate::hash::Sink::new(info.nar_hash().type_());
+  store.nar_from_path(path, TeeWriter::new(&mut *sink, &mut hasher))?;
+
+  // refuse to export paths that have changed
+  let (hash, _) = hasher.finish();
+  ensure!(
+    &hash == info.nar_hash(),
+    "hash of path {} has changed from {} to {}!",
+    store.print_store_path(path),
+    info.nar_hash().encode_with_type(Encoding::Base32),
+    hash.encode_with_type(Encoding::Base32)
+  );
+
+  sink.write_usize(EXPORT_MAGIC)?;
+  sink.write_tag(store.print_store_path(path))?;
+  sink.write_usize(info.references().len())?;
+  for r in info.references() {
+    sink.write_tag(store.print_store_path(r))?;
+  }
+  sink.write_tag(
+    info
+      .deriver()
+      .map_or_else(String::new, |d| store.print_store_path(d)),
+  )?;
+  // no legacy signature
+  sink.write_usize(0)?;
+  Ok(())
+}
+
+fn read_string<R: Read>(source: &mut R) -> Result<String> {
+  Ok(String::from_utf8(archive::read_bytes(source)?)?)
+}
+
+/// Read paths in export format from `source` and add them to `store`.
+/// Returns the imported paths in the order they appeared.
+pub fn import_paths<S: Store + ?Sized, R: Read>(
+  store: &S,
+  mut source: R,
+  check_sigs: CheckSigsFlag,
+) -> Result<Vec<StorePath>> {
+  let mut order = vec![];
+  let mut pending = BTreeMap::new();
+
+  loop {
+    match archive::read_num(&mut source)? {
+      0 => break,
+      1 => {}
+      _ => bail!("input doesn't look like something created by `nix-store --export'"),
+    }
+
+    // the metadata comes after the NAR, so hang on to it until we know where
+    // it goes
+    let mut nar = tempfile::tempfile()?;
+    let mut hasher = crate::hash::Sink::new(HashType::SHA256);
+    archive::copy_nar(&mut source, TeeWriter::new(&mut nar, &mut hasher))?;
+    let (nar_hash, nar_size) = hasher.finish();
+
+    ensure!(
+      archive::read_num(&mut source)? == EXPORT_MAGIC,
+      "Nix archive cannot be imported; wrong format"
+    );
+
+    let path = store.parse_store_path(read_string(&mut source)?)?;
+    let mut info = ValidPathInfo::new(path.clone(), nar_hash);
+    info.nar_size = Some(nar_size as u64);
+
+    for _ in 0..archive::read_num(&mut source)? {
+      info
+        .references
+        .insert(store.parse_store_path(read_string(&mut source)?)?);
+    }
+
+    let deriver = read_string(&mut source)?;
+    if !deriver.is_empty() {
+      info.deriver = Some(store.parse_store_path(deriver)?);
+    }
+
+    // legacy signatures can't be checked, but may still be present
+    if archive::read_num(&mut source)? == 1 {
+      archive::read_bytes(&mut source)?;
+    }
+
+    order.push(path.clone());
+    pending.insert(path, (info, nar));
+  }
+
+  // references have to be valid before their referrers can be registered
+  let paths = pending.keys().cloned().collect::<BTreeSet<_>>();
+  let sorted = topo_sort(&paths, |p| Ok(pending[p].0.references.clone()))?;
+
+  for path in sorted.iter().rev() {
+    let (info, mut nar) = pending.remove(path).unwrap();
+    debug!("importing path {}", store.print_store_path(path));
+    nar.seek(SeekFrom::Start(0))?;
+    store.add_to_store_from_source(info, nar, RepairFlag::NoRepair, check_sigs)?;
+  }
+
+  Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::{binary_cache::LocalBinaryCache, LocalBinaryCacheStore};
+
+  #[test]
+  fn test_export_import() -> Result<()> {
+    Settings::init_for_tests();
+    let dir = tempfile::tempdir()?;
+    let open = |name: &str| -> Result<LocalBinaryCacheStore> {
+      LocalBinaryCacheStore::new(
+        LocalBinaryCache::new(dir.path().join(name))?,
+        "/nix/store".into(),
+      )
+    };
+    let src = open("src")?;
+    let dst = open("dst")?;
+
+    let dep = StorePath::from_base_name("02w9f4k4sf1q5hdf1fp6lq4r7dpmp0xq-glibc-2.31")?;
+    let path = StorePath::from_base_name("7h7qgvs4kgzsn8a6rb273saxyqh4jxlz-hello-2.10")?;
+
+    let dep_nar = archive::dump_to_bytes(9, &b"libc.so.6"[..])?;
+    let dep_info = ValidPathInfo::new(dep.clone(), Hash::hash_bytes(&dep_nar, HashType::SHA256));
+    src.add_to_store_from_source(
+      dep_info,
+      dep_nar.as_slice(),
+      RepairFlag::NoRepair,
+      CheckSigsFlag::NoCheckSigs,
+    )?;
+
+    let contents = format!("#!/nix/store/{}/bin/sh", dep);
+    let nar = archive::dump_to_bytes(contents.len(), contents.as_bytes())?;
+    let mut info = ValidPathInfo::new(path.clone(), Hash::hash_bytes(&nar, HashType::SHA256));
+    info.references.insert(dep.clone());
+    src.add_to_store_from_source(
+      info,
+      nar.as_slice(),
+      RepairFlag::NoRepair,
+      CheckSigsFlag::NoCheckSigs,
+    )?;
+
+    let mut exported = vec![];
+    export_paths(
+      &src,
+      &maplit::btreeset! { path.clone(), dep.clone() },
+      &mut exported,
+    )?;
+
+    // references come first
+    let imported = import_paths(&dst, exported.as_slice(), CheckSigsFlag::NoCheckSigs)?;
+    assert_eq!(imported, vec![dep.clone(), path.clone()]);
+
+    let info = dst.get_path_info(&path)?.expect("path should be valid");
+    assert_eq!(info.references(), &maplit::btreeset! { dep });
+    let mut fetched = vec![];
+    dst.nar_from_path(&path, &mut fetched)?;
+    assert_eq!(fetched, nar);
+
+    Ok(())
+  }
+}
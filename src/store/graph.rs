@@ -0,0 +1,166 @@
+//! Textual representations of the reference graph, as printed by
+//! `nix-store --query`.
+
+use crate::prelude::*;
+use std::collections::BTreeSet;
+
+static TREE_CONN: &str = "├───";
+static TREE_LAST: &str = "└───";
+static TREE_LINE: &str = "│   ";
+static TREE_NULL: &str = "    ";
+
+static COLOURS: &[&str] = &["black", "red", "green", "blue", "magenta", "burlywood"];
+
+fn get_info<S: Store + ?Sized>(store: &S, path: &StorePath) -> Result<Rc<dyn PathInfo>> {
+  store
+    .get_path_info(path)?
+    .ok_or_else(|| anyhow!("path {} is not valid", store.print_store_path(path)))
+}
+
+/// Print the references of `path` as a tree. Paths that have already been
+/// printed are abbreviated with `[...]`.
+pub fn print_tree<S: Store + ?Sized, W: Write>(
+  store: &S,
+  out: &mut W,
+  path: &StorePath,
+  first_pad: &str,
+  tail_pad: &str,
+  done: &mut BTreeSet<StorePath>,
+) -> Result<()> {
+  if !done.insert(path.clone()) {
+    writeln!(out, "{}{} [...]", first_pad, store.print_store_path(path))?;
+    return Ok(());
+  }
+
+  writeln!(out, "{}{}", first_pad, store.print_store_path(path))?;
+
+  let references: BTreeSet<StorePath> = get_info(store, path)?
+    .references()
+    .iter()
+    .filter(|x| *x != path)
+    .cloned()
+    .collect();
+
+  // print dependencies before their dependents; this keeps the tree flat
+  let mut sorted = store.topo_sort_paths(&references)?;
+  sorted.reverse();
+
+  for (i, r) in sorted.iter().enumerate() {
+    let last = i + 1 == sorted.len();
+    print_tree(
+      store,
+      out,
+      r,
+      &format!("{}{}", tail_pad, if last { TREE_LAST } else { TREE_CONN }),
+      &format!("{}{}", tail_pad, if last { TREE_NULL } else { TREE_LINE }),
+      done,
+    )?;
+  }
+
+  Ok(())
+}
+
+/// Print the closure of `roots` in Graphviz format.
+pub fn print_dot_graph<S: Store + ?Sized, W: Write>(
+  store: &S,
+  out: &mut W,
+  roots: BTreeSet<StorePath>,
+) -> Result<()> {
+  let mut work_list = roots;
+  let mut done = BTreeSet::new();
+  let mut colours = COLOURS.iter().cycle();
+
+  writeln!(out, "digraph G {{")?;
+
+  while let Some(path) = work_list.iter().next().cloned() {
+    work_list.remove(&path);
+    if !done.insert(path.clone()) {
+      continue;
+    }
+
+    writeln!(
+      out,
+      "\"{}\" [label = \"{}\", shape = box, style = filled, fillcolor = \"#ff0000\"];",
+      store.print_store_path(&path),
+      path.name
+    )?;
+
+    for r in get_info(store, &path)?.references() {
+      if r != &path {
+        work_list.insert(r.clone());
+        writeln!(
+          out,
+          "\"{}\" -> \"{}\" [color = \"{}\"];",
+          store.print_store_path(r),
+          store.print_store_path(&path),
+          colours.next().unwrap()
+        )?;
+      }
+    }
+  }
+
+  writeln!(out, "}}")?;
+
+  Ok(())
+}
+
+/// Print the closure of `roots` in GraphML format.
+pub fn print_graphml<S: Store + ?Sized, W: Write>(
+  store: &S,
+  out: &mut W,
+  roots: BTreeSet<StorePath>,
+) -> Result<()> {
+  let mut work_list = roots;
+  let mut done = BTreeSet::new();
+
+  write!(
+    out,
+    "<?xml version='1.0' encoding='utf-8'?>\n<graphml \
+     xmlns='http://graphml.graphdrawing.org/xmlns'\n    \
+     xmlns:xsi='http://www.w3.org/2001/XMLSchema-instance'\n    \
+     xsi:schemaLocation='http://graphml.graphdrawing.org/xmlns/1.0/graphml.xsd'>\n<key \
+     id='narSize' for='node' attr.name='narSize' attr.type='int'/><key id='name' for='node' \
+     attr.name='name' attr.type='string'/><key id='type' for='node' attr.name='type' \
+     attr.type='string'/><graph id='G' edgedefault='directed'>\n"
+  )?;
+
+  while let Some(path) = work_list.iter().next().cloned() {
+    work_list.remove(&path);
+    if !done.insert(path.clone()) {
+      continue;
+    }
+
+    let info = get_info(store, &path)?;
+
+    write!(
+      out,
+      "  <node id=\"{}\">\n    <data key=\"narSize\">{}</data>\n    <data \
+       key=\"name\">{}</data>\n    <data key=\"type\">{}</data>\n  </node>\n",
+      store.print_store_path(&path),
+      info.nar_size().unwrap_or(0),
+      path.name,
+      if path.is_derivation() {
+        "derivation"
+      } else {
+        "output-path"
+      }
+    )?;
+
+    for r in info.references() {
+      if r != &path {
+        work_list.insert(r.clone());
+        writeln!(
+          out,
+          "  <edge source=\"{}\" target=\"{}\"/>",
+          store.print_store_path(&path),
+          store.print_store_path(r)
+        )?;
+      }
+    }
+  }
+
+  writeln!(out, "</graph>")?;
+  writeln!(out, "</graphml>")?;
+
+  Ok(())
+}
@@ -1,7 +1,7 @@
 use crate::{path_info::ValidPathInfo, prelude::*, sqlite::Sqlite};
-use rusqlite::{named_params, DatabaseName};
+use rusqlite::{named_params, DatabaseName, OptionalExtension};
 use std::{
-  collections::BTreeSet,
+  collections::{BTreeMap, BTreeSet},
   time::{Duration, SystemTime},
 };
 
@@ -11,11 +11,52 @@ static QUERY_PATH_INFO: &str = "select id, hash, registrationTime, deriver, narS
 static QUERY_REFERENCES: &str =
   "select path from Refs join ValidPaths on reference = id where referrer = :id";
 
+static QUERY_REFERRERS: &str = "select path from Refs join ValidPaths on referrer = id where \
+                                reference = (select id from ValidPaths where path = :path)";
+
 static INSERT_REFERENCE: &str =
   "insert or replace into Refs (referrer, reference) values (:referrer, :reference)";
 
+static QUERY_VALID_PATHS: &str = "select path from ValidPaths";
+
+static INVALIDATE_PATH: &str = "delete from ValidPaths where path = :path";
+
+static UPDATE_PATH_INFO: &str = "update ValidPaths set narSize = :narSize, hash = :hash, deriver \
+                                 = :deriver, ultimate = :ultimate, sigs = :sigs, ca = :ca where \
+                                 path = :path";
+
+static DELETE_REFERENCES: &str = "delete from Refs where referrer = :referrer";
+
+static UPDATE_SIGNATURES: &str = "update ValidPaths set sigs = :sigs where path = :path";
+
 static GET_PATH_ID: &str = "select id from ValidPaths where path = :path";
 
+static ADD_DERIVATION_OUTPUT: &str =
+  "insert or replace into DerivationOutputs (drv, id, path) values (:drv, :id, :path)";
+
+static QUERY_VALID_DERIVERS: &str = "select v.path from DerivationOutputs d join ValidPaths v on \
+                                     d.drv = v.id where d.path = :path";
+
+static QUERY_DERIVATION_OUTPUTS: &str = "select id, path from DerivationOutputs where drv = \
+                                         (select id from ValidPaths where path = :path)";
+
+/// The schema version of databases created from `schema.sql`.
+pub const SCHEMA_VERSION: u32 = 10;
+
+/// The oldest schema version that can be upgraded.
+const OLDEST_SCHEMA_VERSION: u32 = 7;
+
+/// Statements that upgrade the schema from the given version to the next one.
+static MIGRATIONS: &[(u32, &str)] = &[
+  (
+    7,
+    "alter table ValidPaths add column ultimate integer; alter table ValidPaths add column sigs \
+     text;",
+  ),
+  (8, "drop table if exists FailedPaths;"),
+  (9, "alter table ValidPaths add column ca text;"),
+];
+
 static REGISTER_VALID_PATHS: &str =
   "insert or replace into ValidPaths (path, hash, registrationTime, deriver, narSize, ultimate, \
    sigs, ca) values (:path, :hash, :registrationTime, :deriver, :narSize, :ultimate, :sigs, :ca)";
@@ -40,6 +81,26 @@ pub fn init(db: &Sqlite, create: bool) -> Result<()> {
   Ok(())
 }
 
+/// Upgrade a database at schema version `from` to [`SCHEMA_VERSION`].
+pub fn migrate(db: &mut Sqlite, from: u32) -> Result<()> {
+  ensure!(
+    from >= OLDEST_SCHEMA_VERSION,
+    "current Nix store schema is version {}, which is too old to upgrade",
+    from
+  );
+  for (version, statements) in MIGRATIONS.iter().filter(|(v, _)| *v >= from) {
+    info!(
+      "upgrading Nix store schema from version {} to {}",
+      version,
+      version + 1
+    );
+    let txn = db.transaction()?;
+    txn.execute_batch(statements)?;
+    txn.commit()?;
+  }
+  Ok(())
+}
+
 pub fn insert_valid_paths<S: Store + ?Sized>(
   db: &mut Sqlite,
   store: &S,
@@ -48,6 +109,33 @@ pub fn insert_valid_paths<S: Store + ?Sized>(
   let txn = db.transaction()?;
 
   for path_info in &paths {
+    let id = txn
+      .query_row_named(
+        GET_PATH_ID,
+        named_params! {":path": store.print_store_path(&path_info.store_path)},
+        |row| row.get::<_, i64>(0),
+      )
+      .optional()?;
+
+    // replacing the row would give it a new ID and break the references to it,
+    // so update it and let its own references be added again below
+    if let Some(id) = id {
+      txn.execute_named(
+        UPDATE_PATH_INFO,
+        named_params! {
+          ":path": store.print_store_path(&path_info.store_path),
+          ":hash": path_info.nar_hash.encode_with_type(Encoding::Base16),
+          ":deriver": path_info.deriver.as_ref().map(|r|store.print_store_path(r)),
+          ":narSize": path_info.nar_size.unwrap_or(0) as i64,
+          ":ultimate": path_info.ultimate,
+          ":sigs": itertools::join(&path_info.signatures, " "),
+          ":ca": path_info.content_addressed
+        },
+      )?;
+      txn.execute_named(DELETE_REFERENCES, named_params! {":referrer": id})?;
+      continue;
+    }
+
     txn.execute_named(
       REGISTER_VALID_PATHS,
       named_params! {
@@ -58,7 +146,7 @@ pub fn insert_valid_paths<S: Store + ?Sized>(
         ":narSize": path_info.nar_size.unwrap_or(0) as i64,
         ":ultimate": path_info.ultimate,
         ":sigs": itertools::join(&path_info.signatures, " "),
-        ":ca": ""
+        ":ca": path_info.content_addressed
       },
     )?;
   }
@@ -67,13 +155,26 @@ pub fn insert_valid_paths<S: Store + ?Sized>(
   // XXX: these are done in two separate steps because the outputs may depend on
   // each other, but are not necessarily topologically sorted
   let txn = db.transaction()?;
-  for path_info in paths.iter().filter(|x| !x.references.is_empty()) {
+  for path_info in &paths {
     let from_id = txn.query_row_named(
       GET_PATH_ID,
       named_params! {":path": store.print_store_path(path_info.store_path())},
       |row| row.get::<_, i64>(0),
     )?;
 
+    if path_info.store_path.is_derivation() {
+      for (id, output) in &store.read_derivation(&path_info.store_path)?.outputs {
+        txn.execute_named(
+          ADD_DERIVATION_OUTPUT,
+          named_params! {
+            ":drv": from_id,
+            ":id": id,
+            ":path": store.print_store_path(&output.path)
+          },
+        )?;
+      }
+    }
+
     for r in &path_info.references {
       let to_id = txn.query_row_named(
         GET_PATH_ID,
@@ -115,10 +216,18 @@ pub fn get_path_info<S: Store + ?Sized>(
         references: Default::default(),
         registration_time: SystemTime::UNIX_EPOCH
           + Duration::from_secs(row.get::<_, i64>("registrationTime")?.try_into()?),
-        nar_size: Some(row.get::<_, i64>("narSize")?.try_into()?),
-        signatures: Default::default(),
-        content_addressed: row.get("ca")?,
-        ultimate: row.get("ultimate")?,
+        nar_size: row
+          .get::<_, Option<i64>>("narSize")?
+          .map(|x| x.try_into())
+          .transpose()?,
+        signatures: row
+          .get::<_, Option<String>>("sigs")?
+          .map(|s| s.split_whitespace().map(String::from).collect())
+          .unwrap_or_default(),
+        content_addressed: row
+          .get::<_, Option<String>>("ca")?
+          .filter(|x| !x.is_empty()),
+        ultimate: row.get::<_, Option<bool>>("ultimate")?.unwrap_or(false),
       })
     },
   )?;
@@ -132,6 +241,21 @@ pub fn get_path_info<S: Store + ?Sized>(
   }
 }
 
+pub fn update_signatures<S: Store + ?Sized>(
+  db: &Sqlite,
+  store: &S,
+  path_info: &ValidPathInfo,
+) -> Result<()> {
+  db.execute_named(
+    UPDATE_SIGNATURES,
+    named_params! {
+      ":path": store.print_store_path(&path_info.store_path),
+      ":sigs": itertools::join(&path_info.signatures, " "),
+    },
+  )?;
+  Ok(())
+}
+
 pub fn get_references<S: Store + ?Sized>(
   db: &Sqlite,
   store: &S,
@@ -146,3 +270,110 @@ pub fn get_references<S: Store + ?Sized>(
     .collect();
   items
 }
+
+pub fn get_referrers<S: Store + ?Sized>(
+  db: &Sqlite,
+  store: &S,
+  path: &StorePath,
+) -> Result<BTreeSet<StorePath>> {
+  let mut stmt0 = db.prepare(QUERY_REFERRERS)?;
+
+  let items = stmt0
+    .query_and_then_named(
+      named_params! { ":path": store.print_store_path(path) },
+      |row| store.parse_store_path(row.get::<_, String>("path")?),
+    )?
+    .collect();
+  items
+}
+
+pub fn query_valid_paths<S: Store + ?Sized>(db: &Sqlite, store: &S) -> Result<BTreeSet<StorePath>> {
+  let mut stmt0 = db.prepare(QUERY_VALID_PATHS)?;
+
+  let items = stmt0
+    .query_and_then(rusqlite::NO_PARAMS, |row| {
+      store.parse_store_path(row.get::<_, String>("path")?)
+    })?
+    .collect();
+  items
+}
+
+pub fn invalidate_path<S: Store + ?Sized>(db: &Sqlite, store: &S, path: &StorePath) -> Result<()> {
+  db.execute_named(
+    INVALIDATE_PATH,
+    named_params! { ":path": store.print_store_path(path) },
+  )?;
+  Ok(())
+}
+
+pub fn query_valid_derivers<S: Store + ?Sized>(
+  db: &Sqlite,
+  store: &S,
+  path: &StorePath,
+) -> Result<BTreeSet<StorePath>> {
+  let mut stmt0 = db.prepare(QUERY_VALID_DERIVERS)?;
+
+  let items = stmt0
+    .query_and_then_named(
+      named_params! { ":path": store.print_store_path(path) },
+      |row| store.parse_store_path(row.get::<_, String>("path")?),
+    )?
+    .collect();
+  items
+}
+
+pub fn query_derivation_outputs<S: Store + ?Sized>(
+  db: &Sqlite,
+  store: &S,
+  path: &StorePath,
+) -> Result<BTreeMap<String, StorePath>> {
+  let mut stmt0 = db.prepare(QUERY_DERIVATION_OUTPUTS)?;
+
+  let items = stmt0
+    .query_and_then_named(
+      named_params! { ":path": store.print_store_path(path) },
+      |row| {
+        Ok((
+          row.get::<_, String>("id")?,
+          store.parse_store_path(row.get::<_, String>("path")?)?,
+        ))
+      },
+    )?
+    .collect();
+  items
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_migrate() -> Result<()> {
+    let mut db = Sqlite::open(":memory:")?;
+    db.execute_batch(
+      "create table ValidPaths (id integer primary key, path text unique not null); create \
+       table FailedPaths (path text); insert into ValidPaths (id, path) values (42, \
+       '/nix/store/x');",
+    )?;
+    migrate(&mut db, 7)?;
+    db.execute_batch(
+      "insert into ValidPaths (path, ultimate, sigs, ca) values ('/nix/store/y', 1, '', '')",
+    )?;
+    let old = db.query_row(
+      "select id, ultimate, sigs, ca from ValidPaths where path = '/nix/store/x'",
+      rusqlite::NO_PARAMS,
+      |row| {
+        Ok((
+          row.get::<_, i64>(0)?,
+          row.get::<_, Option<bool>>(1)?,
+          row.get::<_, Option<String>>(2)?,
+          row.get::<_, Option<String>>(3)?,
+        ))
+      },
+    )?;
+    assert_eq!(old, (42, None, None, None));
+    assert!(db.execute_batch("select * from FailedPaths").is_err());
+    assert!(migrate(&mut db, 6).is_err());
+    Ok(())
+  }
+}
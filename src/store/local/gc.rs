@@ -1,8 +1,63 @@
+use super::{db, LocalStore};
 use crate::{
+  prelude::*,
+  store::topo_sort,
   sync::fs_lock::{FsExt2, LockType},
-  util::*,
 };
-use std::{fs::File, path::Path};
+use regex::Regex;
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  fs::{File, OpenOptions},
+};
+use unix::sys::statvfs::statvfs;
+
+static CENSORED: &str = "{censored}";
+
+/// Maps every root to the links (symlinks, profiles, `/proc` entries or temp
+/// root files) that keep it alive.
+pub type Roots = BTreeMap<StorePath, BTreeSet<PathBuf>>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GcAction {
+  /// Return the set of paths reachable from the roots.
+  ReturnLive,
+  /// Return the set of paths not reachable from the roots.
+  ReturnDead,
+  /// Delete every path not reachable from the roots.
+  DeleteDead,
+  /// Delete the paths in [`GcOptions::paths_to_delete`], along with any dead
+  /// paths referring to them.
+  DeleteSpecific,
+}
+
+#[derive(Debug)]
+pub struct GcOptions {
+  pub action: GcAction,
+  /// If set, [`GcAction::DeleteSpecific`] does not check whether the paths
+  /// are still reachable from a root.
+  pub ignore_liveness: bool,
+  pub paths_to_delete: BTreeSet<StorePath>,
+  /// Stop deleting once this many bytes have been freed.
+  pub max_freed: u64,
+}
+
+impl Default for GcOptions {
+  fn default() -> Self {
+    Self {
+      action: GcAction::DeleteDead,
+      ignore_liveness: false,
+      paths_to_delete: Default::default(),
+      max_freed: u64::MAX,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct GcResults {
+  /// Depending on the action, the live, dead or deleted paths.
+  pub paths: BTreeSet<String>,
+  pub bytes_freed: u64,
+}
 
 pub fn open_gc_lock<P: AsRef<Path>>(state_dir: P, l: LockType) -> Result<File> {
   let gc_lock = state_dir.as_ref().join("gc.lock");
@@ -14,3 +69,600 @@ pub fn open_gc_lock<P: AsRef<Path>>(state_dir: P, l: LockType) -> Result<File> {
   }
   Ok(f)
 }
+
+/// The name of the store path that a lock file or build sandbox belongs to.
+fn temp_file_owner(name: &str) -> Option<&str> {
+  name
+    .strip_suffix(".lock")
+    .or_else(|| name.strip_suffix(".chroot"))
+}
+
+impl LocalStore {
+  /// Find all the roots of the store: the symlinks in `gcroots` and
+  /// `profiles`, paths in use by running processes, and temp roots. If
+  /// `censor` is set, links that could reveal information about other users
+  /// are replaced with `{censored}`.
+  pub fn find_roots(&self, censor: bool) -> Result<Roots> {
+    let mut roots = self.find_roots_no_temp(censor)?;
+    self.read_temp_roots(censor, false, &mut roots)?;
+    Ok(roots)
+  }
+
+  fn find_roots_no_temp(&self, censor: bool) -> Result<Roots> {
+    let mut roots = Roots::new();
+    let state_dir = &self.state_dir;
+    self.find_roots_in(&state_dir.join("gcroots"), &mut roots)?;
+    self.find_roots_in(&state_dir.join("profiles"), &mut roots)?;
+    self.find_runtime_roots(censor, &mut roots)?;
+    Ok(roots)
+  }
+
+  fn find_roots_in(&self, path: &Path, roots: &mut Roots) -> Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      x => x?,
+    };
+    let ty = meta.file_type();
+
+    if ty.is_dir() {
+      for entry in fs::read_dir(path)? {
+        self.find_roots_in(&entry?.path(), roots)?;
+      }
+    } else if ty.is_symlink() {
+      let target = resolve_link(path)?;
+      if self.is_in_store(&target) {
+        self.found_root(path, &target, roots)?;
+      } else {
+        // indirect roots, e.g. gcroots/auto/<hash> -> /home/me/result -> /nix/store/...
+        match fs::symlink_metadata(&target) {
+          Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let auto_dir = self.state_dir.join("gcroots").join("auto");
+            if path.starts_with(auto_dir) {
+              info!(
+                "removing stale link from `{}' to `{}'",
+                path.display(),
+                target.display()
+              );
+              fs::remove_file(path)?;
+            }
+          }
+          Err(e) => return Err(e.into()),
+          Ok(m) if m.file_type().is_symlink() => {
+            let target2 = resolve_link(&target)?;
+            if self.is_in_store(&target2) {
+              self.found_root(&target, &target2, roots)?;
+            }
+          }
+          Ok(_) => {}
+        }
+      }
+    } else if ty.is_file() {
+      // a regular file whose name is the base name of a store path
+      let store_path = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .and_then(|x| StorePath::from_base_name(x).ok());
+      if let Some(p) = store_path {
+        if self.is_valid_path(&p)? {
+          roots.entry(p).or_default().insert(path.into());
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  fn found_root(&self, link: &Path, target: &Path, roots: &mut Roots) -> Result<()> {
+    match self.to_store_path(target) {
+      Ok(p) if self.is_valid_path(&p)? => {
+        roots.entry(p).or_default().insert(link.into());
+      }
+      _ => info!(
+        "skipping invalid root from `{}' to `{}'",
+        link.display(),
+        target.display()
+      ),
+    }
+    Ok(())
+  }
+
+  fn find_runtime_roots(&self, censor: bool, roots: &mut Roots) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+      return Ok(());
+    }
+
+    let mut unchecked: HashMap<PathBuf, BTreeSet<PathBuf>> = HashMap::new();
+    let store_regex = Regex::new(&format!(
+      r"{}/[0-9a-z]+[0-9a-zA-Z\+\-\._\?=]*",
+      regex::escape(&self.store_path().to_string_lossy())
+    ))?;
+
+    fn read_link(link: PathBuf, unchecked: &mut HashMap<PathBuf, BTreeSet<PathBuf>>) {
+      if let Ok(target) = fs::read_link(&link) {
+        unchecked.entry(target).or_default().insert(link);
+      }
+    }
+
+    for entry in fs::read_dir("/proc")? {
+      let entry = entry?;
+      let is_pid = entry
+        .file_name()
+        .to_str()
+        .map_or(false, |x| x.bytes().all(|b| b.is_ascii_digit()));
+      if !is_pid {
+        continue;
+      }
+      let proc_dir = entry.path();
+
+      read_link(proc_dir.join("exe"), &mut unchecked);
+      read_link(proc_dir.join("cwd"), &mut unchecked);
+
+      // processes may exit or be inaccessible to us while we scan them
+      if let Ok(fds) = fs::read_dir(proc_dir.join("fd")) {
+        for fd in fds.flatten() {
+          read_link(fd.path(), &mut unchecked);
+        }
+      }
+
+      for file in &["maps", "environ"] {
+        let source = proc_dir.join(file);
+        if let Ok(contents) = fs::read(&source) {
+          for m in store_regex.find_iter(&String::from_utf8_lossy(&contents)) {
+            unchecked
+              .entry(PathBuf::from(m.as_str()))
+              .or_default()
+              .insert(source.clone());
+          }
+        }
+      }
+    }
+
+    for (target, links) in unchecked {
+      if !self.is_in_store(&target) {
+        continue;
+      }
+      if let Ok(p) = self.to_store_path(&target) {
+        if self.is_valid_path(&p)? {
+          debug!("got additional root `{}'", self.print_store_path(&p));
+          let entry = roots.entry(p).or_default();
+          if censor {
+            entry.insert(CENSORED.into());
+          } else {
+            entry.extend(links);
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Reads the temp roots of all running processes. The returned files hold
+  /// read locks, preventing their owners from registering new temp roots
+  /// until they are dropped. Stale files are only cleaned up if `gc` is set;
+  /// otherwise the files are opened read-only, so that roots can be listed
+  /// without write access to other users' files.
+  fn read_temp_roots(&self, censor: bool, gc: bool, roots: &mut Roots) -> Result<Vec<File>> {
+    let mut files = vec![];
+
+    for entry in fs::read_dir(&self.temproots_dir)? {
+      let entry = entry?;
+      let path = entry.path();
+      let pid = entry.file_name().to_string_lossy().into_owned();
+
+      debug!("reading temporary root file `{}'", path.display());
+      let mut file = match OpenOptions::new().read(true).write(gc).open(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        x => x?,
+      };
+
+      // if we can get a write lock, the owning process has died
+      if gc && file.try_lock(LockType::Write)? {
+        info!("removing stale temporary roots file `{}'", path.display());
+        fs::remove_file(&path)?;
+        file.write_all(b"d")?;
+        continue;
+      }
+
+      // wait until the owner is done writing
+      file.lock(LockType::Read)?;
+
+      let mut contents = String::new();
+      file.read_to_string(&mut contents)?;
+
+      for root in contents.split('\0').filter(|x| !x.is_empty()) {
+        debug!("got temporary root `{}'", root);
+        roots
+          .entry(self.parse_store_path(root)?)
+          .or_default()
+          .insert(if censor {
+            CENSORED.into()
+          } else {
+            format!("{{temp:{}}}", pid).into()
+          });
+      }
+
+      files.push(file);
+    }
+
+    Ok(files)
+  }
+
+  pub(super) fn query_all_path_infos(&self) -> Result<HashMap<StorePath, ValidPathInfo>> {
+    let conn = self.db.lock();
+    db::query_valid_paths(&conn, self)?
+      .into_iter()
+      .map(|path| {
+        let info = db::get_path_info(&conn, self, &path)?
+          .ok_or_else(|| anyhow!("path {} disappeared", self.print_store_path(&path)))?;
+        Ok((path, info))
+      })
+      .collect()
+  }
+
+  fn compute_live(
+    &self,
+    roots: &Roots,
+    infos: &HashMap<StorePath, ValidPathInfo>,
+  ) -> Result<BTreeSet<StorePath>> {
+    let settings = settings();
+    let mut live = BTreeSet::new();
+    let mut todo = roots.keys().cloned().collect::<Vec<_>>();
+
+    while let Some(path) = todo.pop() {
+      if !live.insert(path.clone()) {
+        continue;
+      }
+      let info = match infos.get(&path) {
+        Some(x) => x,
+        None => continue,
+      };
+
+      todo.extend(info.references.iter().cloned());
+
+      if settings.gc_keep_derivations {
+        if let Some(d) = info.deriver.as_ref().filter(|d| infos.contains_key(*d)) {
+          todo.push(d.clone());
+        }
+      }
+
+      if settings.gc_keep_outputs && path.is_derivation() {
+        for out in self.read_derivation(&path)?.out_paths() {
+          if infos.contains_key(out) {
+            todo.push(out.clone());
+          }
+        }
+      }
+    }
+
+    Ok(live)
+  }
+
+  /// Entries of the store directory that look like store paths but are
+  /// neither valid nor temp roots, e.g. left over from interrupted builds.
+  fn find_invalid_paths(
+    &self,
+    roots: &Roots,
+    infos: &HashMap<StorePath, ValidPathInfo>,
+  ) -> Result<BTreeSet<StorePath>> {
+    let mut paths = BTreeSet::new();
+    for entry in fs::read_dir(&self.real_store_dir)? {
+      let name = entry?.file_name();
+      let name = match name.to_str() {
+        Some(x) => x,
+        None => continue,
+      };
+      if name.starts_with('.') {
+        continue;
+      }
+      // lock files and sandboxes are only garbage if their path isn't in use
+      if let Some(owner) = temp_file_owner(name) {
+        if StorePath::from_base_name(owner).map_or(false, |p| roots.contains_key(&p)) {
+          continue;
+        }
+      }
+      if let Ok(p) = StorePath::from_base_name(name) {
+        if !infos.contains_key(&p) && !roots.contains_key(&p) {
+          paths.insert(p);
+        }
+      }
+    }
+    Ok(paths)
+  }
+
+  pub fn collect_garbage(&self, options: &GcOptions) -> Result<GcResults> {
+    let mut results = GcResults::default();
+
+    let _gc_lock = open_gc_lock(&self.state_dir, LockType::Write)?;
+
+    let mut roots = self.find_roots_no_temp(false)?;
+    let _temp_roots = self.read_temp_roots(false, true, &mut roots)?;
+
+    let infos = self.query_all_path_infos()?;
+    let live = if options.ignore_liveness {
+      BTreeSet::new()
+    } else {
+      self.compute_live(&roots, &infos)?
+    };
+
+    if options.action == GcAction::ReturnLive {
+      results.paths = live
+        .iter()
+        .filter(|x| infos.contains_key(*x))
+        .map(|x| self.print_store_path(x))
+        .collect();
+      return Ok(results);
+    }
+
+    let mut referrers: HashMap<&StorePath, BTreeSet<&StorePath>> = HashMap::new();
+    for (path, info) in &infos {
+      for r in info.references.iter().filter(|r| *r != path) {
+        referrers.entry(r).or_default().insert(path);
+      }
+    }
+
+    let (targets, invalid) = if options.action == GcAction::DeleteSpecific {
+      // a path can only be deleted along with everything that refers to it
+      let mut targets = BTreeSet::new();
+      let mut todo = options.paths_to_delete.iter().collect::<Vec<_>>();
+      while let Some(path) = todo.pop() {
+        if live.contains(path) {
+          bail!(
+            "cannot delete path `{}' since it is still alive",
+            self.print_store_path(path)
+          );
+        }
+        if targets.insert(path.clone()) {
+          todo.extend(referrers.get(path).into_iter().flatten());
+        }
+      }
+      let invalid = targets
+        .iter()
+        .filter(|x| !infos.contains_key(*x))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+      (targets, invalid)
+    } else {
+      let targets = infos
+        .keys()
+        .filter(|x| !live.contains(*x))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+      (targets, self.find_invalid_paths(&roots, &infos)?)
+    };
+
+    let sorted = topo_sort(&targets, |path| {
+      Ok(
+        infos
+          .get(path)
+          .map_or_else(Default::default, |x| x.references.clone()),
+      )
+    })?;
+
+    for path in sorted
+      .iter()
+      .filter(|x| infos.contains_key(*x))
+      .chain(&invalid)
+    {
+      if results.bytes_freed >= options.max_freed {
+        info!("deleted more than {} bytes; stopping", options.max_freed);
+        break;
+      }
+
+      let real_path = self.to_real_path(path)?;
+      let name = path.to_string();
+      let owner = temp_file_owner(&name).filter(|_| !infos.contains_key(path));
+
+      if options.action == GcAction::ReturnDead {
+        if owner.is_none() {
+          results.paths.insert(self.print_store_path(path));
+        }
+        continue;
+      }
+
+      // an invalid path may be the output of a running build, which holds
+      // the lock next to it
+      let mut _lock = None;
+      if !infos.contains_key(path) {
+        let mut lock_path = real_path
+          .with_file_name(owner.unwrap_or(&name))
+          .into_os_string();
+        lock_path.push(".lock");
+        match File::open(&lock_path) {
+          Ok(f) => {
+            if !f.try_lock(LockType::Write)? {
+              debug!("skipping active temporary path `{}'", real_path.display());
+              continue;
+            }
+            _lock = Some(f);
+          }
+          Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+          Err(e) => return Err(e.into()),
+        }
+      }
+
+      if infos.contains_key(path) {
+        self.invalidate_path(path)?;
+      }
+      debug!("deleting `{}'", real_path.display());
+      results.bytes_freed += delete_path(&real_path)?;
+      if owner.is_none() {
+        results.paths.insert(self.print_store_path(path));
+      }
+    }
+
+    if options.action != GcAction::ReturnDead {
+      results.bytes_freed += self.remove_unused_links()?;
+    }
+
+    Ok(results)
+  }
+
+  pub(super) fn invalidate_path(&self, path: &StorePath) -> Result<()> {
+    debug!("invalidating path `{}'", self.print_store_path(path));
+    let conn = self.db.lock();
+    db::invalidate_path(&conn, self, path)
+  }
+
+  /// Remove the files in `.links` that are no longer linked into any store
+  /// path.
+  fn remove_unused_links(&self) -> Result<u64> {
+    let mut bytes_freed = 0;
+    let mut bytes_kept = 0;
+
+    for entry in fs::read_dir(&self.links_dir)? {
+      let path = entry?.path();
+      let meta = fs::symlink_metadata(&path)?;
+      if meta.nlink() != 1 {
+        bytes_kept += meta.blocks() * 512;
+        continue;
+      }
+      trace!("deleting unused link `{}'", path.display());
+      fs::remove_file(&path)?;
+      bytes_freed += meta.blocks() * 512;
+    }
+
+    info!(
+      "note: currently hard linking saves {:.2} MiB",
+      bytes_kept as f64 / (1024.0 * 1024.0)
+    );
+
+    Ok(bytes_freed)
+  }
+
+  /// Run the garbage collector if the free space on the store's filesystem
+  /// has dropped below `min-free`, stopping once `max-free` bytes are
+  /// available.
+  pub fn auto_gc(&self) -> Result<()> {
+    let settings = settings();
+    if settings.min_free == 0 {
+      return Ok(());
+    }
+
+    let st = statvfs(&self.real_store_dir)?;
+    let avail = st.blocks_available() as u64 * st.fragment_size() as u64;
+    if avail >= settings.min_free {
+      return Ok(());
+    }
+
+    let to_free = settings.max_free.saturating_sub(avail);
+    info!("running auto-GC to free {} bytes", to_free);
+
+    let results = self.collect_garbage(&GcOptions {
+      max_freed: to_free,
+      ..Default::default()
+    })?;
+
+    info!(
+      "{} store paths deleted, {:.2} MiB freed",
+      results.paths.len(),
+      results.bytes_freed as f64 / (1024.0 * 1024.0)
+    );
+
+    Ok(())
+  }
+}
+
+fn resolve_link(path: &Path) -> Result<PathBuf> {
+  let target = fs::read_link(path)?;
+  Ok(match path.parent() {
+    Some(p) if target.is_relative() => p.join(target),
+    _ => target,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::RepairFlag;
+  use maplit::btreeset;
+  use std::os::unix::fs::symlink;
+
+  /// A store holding `top` (which refers to `dep`), `garbage` and `temp`, with
+  /// `top` registered as a GC root.
+  fn gc_store(root: &Path) -> Result<(LocalStore, [StorePath; 4])> {
+    Settings::init_for_tests();
+    let paths = {
+      // everything a store adds is a temp root until the store is closed
+      let s = LocalStore::open_chroot(root)?;
+      let dep = s.add_text_to_store("dep", "foo", None, RepairFlag::NoRepair)?;
+      let top = s.add_text_to_store("top", "bar", Some(dep.clone()), RepairFlag::NoRepair)?;
+      let garbage = s.add_text_to_store("garbage", "baz", None, RepairFlag::NoRepair)?;
+      let temp = s.add_text_to_store("temp", "quux", None, RepairFlag::NoRepair)?;
+      [top, dep, garbage, temp]
+    };
+
+    let s = LocalStore::open_chroot(root)?;
+    let gcroots = s.state_dir.join("gcroots");
+    fs::create_dir_all(&gcroots)?;
+    symlink(s.print_store_path(&paths[0]), gcroots.join("top"))?;
+    Ok((s, paths))
+  }
+
+  #[test]
+  fn test_collect_garbage() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let (s, [top, dep, garbage, temp]) = gc_store(root.path())?;
+    s.add_temp_root(&temp)?;
+
+    let results = s.collect_garbage(&GcOptions::default())?;
+    assert_eq!(results.paths, btreeset! { s.print_store_path(&garbage) });
+    assert!(!s.is_valid_path(&garbage)?);
+    assert!(!s.to_real_path(&garbage)?.exists());
+    for path in &[top, dep, temp] {
+      assert!(s.is_valid_path(path)?);
+      assert!(s.to_real_path(path)?.exists());
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_delete_live_path() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let (s, [_, dep, ..]) = gc_store(root.path())?;
+
+    let e = s
+      .collect_garbage(&GcOptions {
+        action: GcAction::DeleteSpecific,
+        paths_to_delete: btreeset! { dep.clone() },
+        ..Default::default()
+      })
+      .unwrap_err();
+    assert!(e.to_string().contains("still alive"), "{:#}", e);
+    assert!(s.is_valid_path(&dep)?);
+    assert!(s.to_real_path(&dep)?.exists());
+    Ok(())
+  }
+
+  #[test]
+  fn test_locked_invalid_paths() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let (s, [_, _, garbage, _]) = gc_store(root.path())?;
+
+    // leftovers of a running build and a dead one
+    let busy = s.store_path_for_text("busy", "", None)?;
+    let stale = s.store_path_for_text("stale", "", None)?;
+    let mut locks = vec![];
+    for path in &[&busy, &stale] {
+      let real_path = s.to_real_path(path)?;
+      fs::create_dir(&real_path)?;
+      let mut lock_path = real_path.into_os_string();
+      lock_path.push(".lock");
+      File::create(&lock_path)?;
+      locks.push(PathBuf::from(lock_path));
+    }
+    let busy_lock = File::open(&locks[0])?;
+    assert!(busy_lock.try_lock(LockType::Write)?);
+
+    let results = s.collect_garbage(&GcOptions::default())?;
+    assert_eq!(
+      results.paths,
+      btreeset! { s.print_store_path(&garbage), s.print_store_path(&stale) }
+    );
+    assert!(s.to_real_path(&busy)?.exists());
+    assert!(locks[0].exists());
+    assert!(!s.to_real_path(&stale)?.exists());
+    assert!(!locks[1].exists());
+    Ok(())
+  }
+}
@@ -1,11 +1,19 @@
-use super::{CheckSigsFlag, ClosureOpts, FileIngestionMethod, RepairFlag};
-use crate::{archive, build::Worker, prelude::*, sqlite::Sqlite, sync::fs_lock::*};
+use super::{CheckSigsFlag, FileIngestionMethod, RepairFlag};
+use crate::{
+  archive,
+  build::Worker,
+  path_info::{fixed_output_ca, text_ca},
+  prelude::*,
+  signing,
+  sqlite::Sqlite,
+  sync::fs_lock::*,
+};
 use archive::PathFilter;
 use fs::File;
 use parking_lot::Mutex;
 use std::{
   borrow::Cow,
-  collections::BTreeSet,
+  collections::{BTreeMap, BTreeSet},
   ffi::OsStr,
   fs,
   io::Write,
@@ -15,13 +23,15 @@ use std::{
   rc::Rc,
   sync::Arc,
 };
-use tee_readwrite::TeeWriter;
+use tee_readwrite::{TeeReader, TeeWriter};
 use unix::unistd::*;
 
 pub mod db;
 pub mod gc;
+mod optimise;
+mod verify;
 
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct OptimiseStats {
   pub files_linked: u32,
   pub bytes_freed: u64,
@@ -30,14 +40,27 @@ pub struct OptimiseStats {
 
 #[derive(Debug)]
 pub struct LocalStore {
+  real_store_dir: PathBuf,
+  state_dir: PathBuf,
+  log_dir: PathBuf,
   temproots_dir: PathBuf,
   links_dir: PathBuf,
+  temp_roots_file: Mutex<Option<File>>,
   db: Mutex<Sqlite>,
+  requester: Option<(Uid, Gid)>,
 }
 
 impl Store for LocalStore {
   fn store_path(&self) -> Cow<OsStr> {
-    Cow::Borrowed(settings().paths.nix_store.as_os_str())
+    Cow::Owned(settings().paths.nix_store.clone().into_os_string())
+  }
+
+  fn to_real_path<P: Borrow<StorePath>>(&self, path: P) -> Result<PathBuf> {
+    Ok(self.real_store_dir.join(path.borrow().to_string()))
+  }
+
+  fn log_dir(&self) -> Cow<Path> {
+    Cow::Borrowed(&self.log_dir)
   }
 
   fn add_text_to_store<I: IntoIterator<Item = StorePath>>(
@@ -76,11 +99,13 @@ impl Store for LocalStore {
         let nar_bytes = archive::dump_to_bytes(contents.len(), contents.as_bytes())?;
         let nar_hash = Hash::hash_bytes(&nar_bytes, HashType::SHA256);
 
-        optimise_path(&real_path)?;
+        self.auto_optimise_path(&real_path)?;
 
         let mut path_info = ValidPathInfo::new(dest_path.clone(), nar_hash);
         path_info.nar_size = Some(nar_bytes.len() as u64);
         path_info.references = references_set;
+        path_info.content_addressed = Some(text_ca(&hash));
+        self.sign_path_info(&mut path_info)?;
 
         self.register_valid_path(path_info)?;
       }
@@ -99,21 +124,26 @@ impl Store for LocalStore {
   }
 
   fn add_temp_root(&self, path: &StorePath) -> Result<()> {
-    let file = self.temproots_dir.join(std::process::id().to_string());
-    let mut temp_file = loop {
-      let all_gc_roots = gc::open_gc_lock(&settings().paths.nix_state_dir, LockType::Read)?;
-      delete_path(&file)?;
-      let temproots_file = File::create(&file)?;
-      drop(all_gc_roots);
-      debug!("acquiring read lock on `{}'", file.display());
-      temproots_file.lock(LockType::Read)?;
-      if temproots_file.metadata()?.len() == 0 {
-        break temproots_file;
-      }
-    };
-    debug!("acquiring write lock on `{}'", file.display());
+    let mut temp_roots = self.temp_roots_file.lock();
+    if temp_roots.is_none() {
+      let file = self.temproots_dir.join(std::process::id().to_string());
+      *temp_roots = Some(loop {
+        let all_gc_roots = gc::open_gc_lock(&self.state_dir, LockType::Read)?;
+        delete_path(&file)?;
+        let temproots_file = File::create(&file)?;
+        drop(all_gc_roots);
+        debug!("acquiring read lock on `{}'", file.display());
+        temproots_file.lock(LockType::Read)?;
+        // the garbage collector writes to the file if it decided that it was stale
+        if temproots_file.metadata()?.len() == 0 {
+          break temproots_file;
+        }
+      });
+    }
+    let temp_file = temp_roots.as_mut().unwrap();
+    debug!("acquiring write lock on temp roots file");
     temp_file.lock(LockType::Write)?;
-    temp_file.write_all(self.print_store_path(path).as_bytes())?;
+    temp_file.write_all(format!("{}\0", self.print_store_path(path)).as_bytes())?;
     temp_file.lock(LockType::Read)?;
     Ok(())
   }
@@ -132,11 +162,23 @@ impl Store for LocalStore {
     path_info: I,
     input: R,
     repair: RepairFlag,
-    _check_sigs: CheckSigsFlag,
+    check_sigs: CheckSigsFlag,
   ) -> Result<()> {
     self.add_temp_root(path_info.store_path())?;
 
     if repair.repair() || !self.is_valid_path(path_info.store_path())? {
+      if check_sigs == CheckSigsFlag::CheckSigs
+        && settings().require_sigs
+        && !signing::is_content_addressed(self, &path_info)?
+      {
+        let keys = signing::trusted_public_keys()?;
+        ensure!(
+          signing::check_signatures(self, &path_info, &keys)? > 0,
+          "cannot add path {} because it lacks a valid signature",
+          self.print_store_path(path_info.store_path())
+        );
+      }
+
       let dest = self.to_real_path(path_info.store_path())?;
 
       PathLocks::new().lock(&mut iter::once(&dest), true, None)?;
@@ -144,9 +186,31 @@ impl Store for LocalStore {
       if repair.repair() || !self.is_valid_path(path_info.store_path())? {
         delete_path(&dest)?;
 
-        archive::restore_path(&dest, input)?;
+        let mut hasher = crate::hash::Sink::new(path_info.nar_hash().type_());
+        archive::restore_path(&dest, TeeReader::new(input, &mut hasher, false))?;
+
+        let (nar_hash, nar_size) = hasher.finish();
+        ensure!(
+          &nar_hash == path_info.nar_hash(),
+          "hash mismatch importing path {};\n  specified: {}\n  got:       {}",
+          self.print_store_path(path_info.store_path()),
+          path_info.nar_hash().encode_with_type(Encoding::Base32),
+          nar_hash.encode_with_type(Encoding::Base32)
+        );
+        ensure!(
+          path_info.nar_size().map_or(true, |n| n == nar_size as u64),
+          "size mismatch importing path {};\n  specified: {}\n  got:       {}",
+          self.print_store_path(path_info.store_path()),
+          path_info.nar_size().unwrap_or(0),
+          nar_size
+        );
 
         canonicalise_path_metadata(&dest, None)?;
+        self.auto_optimise_path(&dest)?;
+
+        let mut info = path_info.to_valid_path_info();
+        info.nar_size = Some(nar_size as u64);
+        self.register_valid_path(info)?;
       }
     }
 
@@ -167,15 +231,23 @@ impl Store for LocalStore {
       bail!("add a flat file")
     }
 
-    let tmpdir = tempfile::tempdir_in(Path::new(&self.store_path()))?;
+    let tmpdir = tempfile::tempdir_in(&self.real_store_dir)?;
     let tmp_dest = tmpdir.as_ref().join("x");
 
-    let nar_hash = Arc::new(Mutex::new(crate::hash::Sink::new(hash_type)));
+    // the path is named after a hash of type `hash_type`, but NAR hashes are
+    // always SHA-256
+    let nar_hash = Arc::new(Mutex::new(crate::hash::Sink::new(HashType::SHA256)));
+    let ca_hash = Arc::new(Mutex::new(crate::hash::Sink::new(hash_type)));
     let nh = Arc::clone(&nar_hash);
+    let ch = Arc::clone(&ca_hash);
 
     crossbeam::scope(|s| {
       let reader = make_pipe(s, move |writer| {
-        crate::archive::dump_path(&path, TeeWriter::new(&mut *nh.lock(), writer), filter)
+        crate::archive::dump_path(
+          &path,
+          TeeWriter::new(&mut *nh.lock(), TeeWriter::new(&mut *ch.lock(), writer)),
+          filter,
+        )
       });
 
       crate::archive::restore_path(&tmp_dest, reader)
@@ -183,9 +255,10 @@ impl Store for LocalStore {
     .unwrap()?;
 
     let (nar_hash, nar_size) = nar_hash.lock().finish();
+    let (ca_hash, _) = ca_hash.lock().finish();
 
     let dest_path =
-      self.make_fixed_output_path(ingest_method, &nar_hash, name, &mut iter::empty(), false)?;
+      self.make_fixed_output_path(ingest_method, &ca_hash, name, &mut iter::empty(), false)?;
 
     self.add_temp_root(&dest_path)?;
 
@@ -197,10 +270,12 @@ impl Store for LocalStore {
         fs::rename(tmp_dest, &real_path)?;
 
         canonicalise_path_metadata(&real_path, None)?;
-        optimise_path(&real_path)?;
+        self.auto_optimise_path(&real_path)?;
 
         let mut pi = ValidPathInfo::new(dest_path.clone(), nar_hash);
         pi.nar_size = Some(nar_size as _);
+        pi.content_addressed = Some(fixed_output_ca(true, &ca_hash));
+        self.sign_path_info(&mut pi)?;
         self.register_valid_path(pi)?;
       }
     }
@@ -210,44 +285,118 @@ impl Store for LocalStore {
     Ok(dest_path)
   }
 
+  fn nar_from_path<W: Write>(&self, path: &StorePath, sink: W) -> Result<()> {
+    ensure!(
+      self.is_valid_path(path)?,
+      "path {} is not valid",
+      self.print_store_path(path)
+    );
+    archive::dump_path(self.to_real_path(path)?, sink, &PathFilter::none())?;
+    Ok(())
+  }
+
+  fn query_valid_derivers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    db::query_valid_derivers(&self.db.lock(), self, path)
+  }
+
+  fn query_derivation_outputs(&self, path: &StorePath) -> Result<BTreeMap<String, StorePath>> {
+    db::query_derivation_outputs(&self.db.lock(), self, path)
+  }
+
+  fn add_signatures(&self, path: &StorePath, sigs: &BTreeSet<String>) -> Result<()> {
+    let conn = self.db.lock();
+    let mut info = db::get_path_info(&conn, self, path)?
+      .ok_or_else(|| anyhow!("path {} is not valid", self.print_store_path(path)))?;
+    info.signatures.extend(sigs.iter().cloned());
+    db::update_signatures(&conn, self, &info)
+  }
+
   fn build_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
     let mut worker = Worker::with_store(self);
+    worker.set_requester(self.requester);
     for path in paths {
       worker.add_needed(&path.path)?;
     }
+    if let Err(e) = self.auto_gc() {
+      error!("auto-GC failed: {:#}", e);
+    }
     worker.build()
   }
 
-  fn compute_closure(
-    &self,
-    path: &StorePath,
-    closure: &mut BTreeSet<StorePath>,
-    options: ClosureOpts,
-  ) -> Result<()> {
-    if !closure.insert(path.clone()) {
-      return Ok(());
-    }
-
-    let info = self
-      .get_path_info(path)?
-      .ok_or_else(|| anyhow!("path {} is invalid", self.print_store_path(path)))?;
-
-    for r in info.references() {
-      if r != path {
-        self.compute_closure(r, closure, options)?;
-      }
+  fn check_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
+    let mut worker = Worker::with_store(self);
+    worker.set_requester(self.requester);
+    for path in paths {
+      worker.add_check(&path.path)?;
     }
+    worker.build()
+  }
 
-    Ok(())
+  fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    let conn = self.db.lock();
+    db::get_referrers(&conn, self, path)
   }
 }
 
 impl LocalStore {
+  /// Everything the database knows about `path`, including the fields that
+  /// [`PathInfo`] doesn't expose.
+  pub fn query_path_info(&self, path: &StorePath) -> Result<Option<ValidPathInfo>> {
+    let conn = self.db.lock();
+    db::get_path_info(&conn, self, path)
+  }
+
+  pub fn query_all_valid_paths(&self) -> Result<BTreeSet<StorePath>> {
+    let conn = self.db.lock();
+    db::query_valid_paths(&conn, self)
+  }
+
+  /// The user builds are done for, if it isn't the one running them, e.g. a
+  /// daemon client.
+  pub fn requester(&self) -> Option<(Uid, Gid)> {
+    self.requester
+  }
+
+  pub fn set_requester(&mut self, uid: Uid, gid: Gid) {
+    self.requester = Some((uid, gid));
+  }
+
   pub fn open() -> Result<Self> {
     let settings = settings();
+    let paths = &settings.paths;
+    Self::open_in(
+      paths.nix_store.clone(),
+      paths.nix_state_dir.clone(),
+      paths.nix_log_dir.clone(),
+    )
+  }
 
-    let db_dir = settings.paths.nix_state_dir.join("db");
+  /// Open a store whose files live under `root` (e.g. in `root/nix/store`)
+  /// while its paths still start with the usual store directory, so that
+  /// paths can be built without write access to the real store.
+  pub fn open_chroot<P: AsRef<Path>>(root: P) -> Result<Self> {
+    let root = root.as_ref();
+    ensure!(
+      root.is_absolute(),
+      "store root `{}' is not an absolute path",
+      root.display()
+    );
+    let under_root = |p: &Path| root.join(p.strip_prefix("/").unwrap_or(p));
+    let settings = settings();
+    let paths = &settings.paths;
+    Self::open_in(
+      under_root(&paths.nix_store),
+      under_root(&paths.nix_state_dir),
+      under_root(&paths.nix_log_dir),
+    )
+  }
+
+  fn open_in(real_store_dir: PathBuf, state_dir: PathBuf, log_dir: PathBuf) -> Result<Self> {
+    let settings = settings();
 
+    let db_dir = state_dir.join("db");
+
+    fs::create_dir_all(&real_store_dir)?;
     fs::create_dir_all(&db_dir)?;
 
     let reserved_path = db_dir.join("reserved");
@@ -279,23 +428,48 @@ impl LocalStore {
     }
 
     let cur_schema = match fs::read_to_string(&schema_path) {
-      Ok(s) => Some(s.parse::<u32>()?),
-      Err(_) => None,
+      Ok(s) => Some(
+        s.trim()
+          .parse::<u32>()
+          .with_context(|| format!("`{}' is corrupt", schema_path.display()))?,
+      ),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e.into()),
     };
+    if let Some(v) = cur_schema {
+      ensure!(
+        v <= db::SCHEMA_VERSION,
+        "current Nix store schema is version {}, but I only support {}",
+        v,
+        db::SCHEMA_VERSION
+      );
+    }
 
-    let sqlite = Sqlite::open(&db_dir.join("db.sqlite"))?;
+    let mut sqlite = Sqlite::open(&db_dir.join("db.sqlite"))?;
 
-    if cur_schema.is_none() {
-      db::init(&sqlite, true)?;
-      fs::write(&schema_path, "10")?;
-    } else {
-      db::init(&sqlite, false)?;
+    match cur_schema {
+      None => {
+        db::init(&sqlite, true)?;
+        fs::write(&schema_path, db::SCHEMA_VERSION.to_string())?;
+      }
+      Some(v) => {
+        db::init(&sqlite, false)?;
+        if v < db::SCHEMA_VERSION {
+          db::migrate(&mut sqlite, v)?;
+          fs::write(&schema_path, db::SCHEMA_VERSION.to_string())?;
+        }
+      }
     }
 
     let this = Self {
-      temproots_dir: settings.paths.nix_state_dir.join("gcroots"),
-      links_dir: settings.paths.nix_store.join(".links"),
+      temproots_dir: state_dir.join("temproots"),
+      links_dir: real_store_dir.join(".links"),
+      real_store_dir,
+      state_dir,
+      log_dir,
+      temp_roots_file: Mutex::new(None),
       db: Mutex::new(sqlite),
+      requester: None,
     };
     fs::create_dir_all(&this.temproots_dir)?;
     fs::create_dir_all(&this.links_dir)?;
@@ -306,12 +480,32 @@ impl LocalStore {
 #[cfg(test)]
 mod tests {
   use super::*;
+
+  #[test]
+  fn test_path_info_round_trip() -> Result<()> {
+    Settings::init_for_tests();
+    let root = tempfile::tempdir()?;
+    let s = LocalStore::open_chroot(root.path())?;
+    let dep = s.add_text_to_store("dep", "foo", None, RepairFlag::NoRepair)?;
+    let path = s.add_text_to_store("top", "bar", Some(dep.clone()), RepairFlag::NoRepair)?;
+
+    let info = s.get_path_info(&path)?.unwrap();
+    assert_eq!(info.references(), &maplit::btreeset! { dep });
+    assert_eq!(info.nar_size(), Some(120));
+    assert_eq!(
+      info.content_addressed(),
+      Some(text_ca(&Hash::hash_str("bar", HashType::SHA256)).as_str())
+    );
+    assert!(signing::is_content_addressed(&s, &*info)?);
+    Ok(())
+  }
+
   #[test]
   fn test_glibc_thingy() -> Result<()> {
-    let s = LocalStore::open()?;
-    let bootstrap = s.parse_store_path(
-      "/tmp/rix-store/nix/store/q4z8prj9sd3jcrn3x1xc0f5zna40ma5a-bootstrap-stage0-glibc.drv",
-    )?;
+    Settings::init_for_tests();
+    let s = LocalStore::open_chroot("/tmp/rix-store")?;
+    let bootstrap =
+      s.parse_store_path("/nix/store/q4z8prj9sd3jcrn3x1xc0f5zna40ma5a-bootstrap-stage0-glibc.drv")?;
     let drv = s.read_derivation(&bootstrap)?;
 
     let mut pathset = BTreeSet::new();
@@ -0,0 +1,55 @@
+use super::{LocalStore, OptimiseStats};
+use crate::prelude::*;
+use std::collections::HashSet;
+
+impl LocalStore {
+  /// Hard-link the files in the freshly added `path` to identical files
+  /// elsewhere in the store, if `auto-optimise-store` is enabled.
+  pub(super) fn auto_optimise_path(&self, path: &Path) -> Result<()> {
+    if !settings().auto_optimise_store {
+      return Ok(());
+    }
+    let mut stats = OptimiseStats::default();
+    optimise_path(&self.real_store_dir, path, &mut HashSet::new(), &mut stats)?;
+    debug!(
+      "{} files hard-linked, {} bytes freed",
+      stats.files_linked, stats.bytes_freed
+    );
+    Ok(())
+  }
+
+  /// The inodes of every file in `.links`. Files with these inodes have
+  /// already been deduplicated, which lets an interrupted optimisation pick
+  /// up where it left off without hashing everything again.
+  fn load_inode_hash(&self) -> Result<HashSet<u64>> {
+    let mut inodes = HashSet::new();
+    for entry in fs::read_dir(&self.links_dir)? {
+      inodes.insert(entry?.metadata()?.ino());
+    }
+    debug!("loaded {} hash inodes", inodes.len());
+    Ok(inodes)
+  }
+
+  /// Deduplicate every valid path in the store.
+  pub fn optimise_store(&self) -> Result<OptimiseStats> {
+    let mut stats = OptimiseStats::default();
+    let mut inodes = self.load_inode_hash()?;
+
+    for path in &self.query_all_valid_paths()? {
+      self.add_temp_root(path)?;
+      // it may have been garbage collected since we listed the paths
+      if !self.is_valid_path(path)? {
+        continue;
+      }
+      info!("optimising path {}", self.print_store_path(path));
+      optimise_path(
+        &self.real_store_dir,
+        &self.to_real_path(path)?,
+        &mut inodes,
+        &mut stats,
+      )?;
+    }
+
+    Ok(stats)
+  }
+}
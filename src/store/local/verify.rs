@@ -0,0 +1,121 @@
+use super::{gc, LocalStore};
+use crate::{
+  archive::{self, PathFilter},
+  prelude::*,
+  store::{topo_sort, RepairFlag},
+  sync::fs_lock::LockType,
+};
+use std::collections::{BTreeSet, HashMap};
+
+impl LocalStore {
+  /// Check that every valid path still exists and, if `check_contents` is
+  /// set, that its NAR hash matches the one in the database. Missing paths
+  /// with no valid referrers are simply invalidated; other problems are
+  /// fixed by substituting or rebuilding the path if `repair` is set.
+  ///
+  /// Returns `true` if any errors were left unfixed.
+  pub fn verify_store(&self, check_contents: bool, repair: RepairFlag) -> Result<bool> {
+    let mut errors = false;
+
+    // keep the garbage collector from deleting things out from under us
+    let _gc_lock = gc::open_gc_lock(&self.state_dir, LockType::Read)?;
+
+    info!("reading the Nix store...");
+    let infos = self.query_all_path_infos()?;
+
+    let mut referrers: HashMap<&StorePath, BTreeSet<&StorePath>> = HashMap::new();
+    for (path, info) in &infos {
+      for r in info.references.iter().filter(|r| *r != path) {
+        referrers.entry(r).or_default().insert(path);
+      }
+    }
+
+    let paths = infos.keys().cloned().collect::<BTreeSet<_>>();
+    let sorted = topo_sort(&paths, |p| Ok(infos[p].references.clone()))?;
+
+    info!("checking path existence...");
+    let mut invalidated = BTreeSet::new();
+    // referrers come first, so by the time we look at a path we know whether
+    // everything referring to it has been invalidated
+    for path in &sorted {
+      if self.to_real_path(path)?.symlink_metadata().is_ok() {
+        continue;
+      }
+
+      let still_referenced = referrers
+        .get(path)
+        .map_or(false, |r| r.iter().any(|r| !invalidated.contains(*r)));
+
+      if !still_referenced {
+        error!(
+          "path {} disappeared, removing from database...",
+          self.print_store_path(path)
+        );
+        self.invalidate_path(path)?;
+        invalidated.insert(path.clone());
+      } else if repair.repair() {
+        error!(
+          "path {} disappeared, trying to repair...",
+          self.print_store_path(path)
+        );
+        errors |= !self.try_repair_path(path);
+      } else {
+        error!(
+          "path {} disappeared, but it still has valid referrers!",
+          self.print_store_path(path)
+        );
+        errors = true;
+      }
+    }
+
+    if check_contents {
+      info!("checking hashes...");
+      for path in sorted.iter().filter(|p| !invalidated.contains(*p)) {
+        let info = &infos[path];
+        let mut sink = crate::hash::Sink::new(info.nar_hash.type_());
+        if let Err(e) = archive::dump_path(self.to_real_path(path)?, &mut sink, &PathFilter::none())
+        {
+          error!(
+            "unable to read path {}: {:#}",
+            self.print_store_path(path),
+            e
+          );
+          errors = true;
+          continue;
+        }
+        let (hash, _) = sink.finish();
+
+        if hash == info.nar_hash {
+          continue;
+        }
+
+        error!(
+          "path {} was modified! expected hash {}, got {}",
+          self.print_store_path(path),
+          info.nar_hash.encode_with_type(Encoding::Base32),
+          hash.encode_with_type(Encoding::Base32)
+        );
+        if repair.repair() {
+          errors |= !self.try_repair_path(path);
+        } else {
+          errors = true;
+        }
+      }
+    }
+
+    Ok(errors)
+  }
+
+  fn try_repair_path(&self, path: &StorePath) -> bool {
+    match crate::build::repair_path(self, path) {
+      Ok(()) => {
+        info!("repaired {}", self.print_store_path(path));
+        true
+      }
+      Err(e) => {
+        error!("unable to repair {}: {:#}", self.print_store_path(path), e);
+        false
+      }
+    }
+  }
+}
@@ -0,0 +1,205 @@
+//! A store that keeps paths in memory and never touches the filesystem, for
+//! evaluating expressions without a real store.
+
+use super::{CheckSigsFlag, FileIngestionMethod, RepairFlag};
+use crate::{
+  archive::{self, PathFilter},
+  path_info::{fixed_output_ca, text_ca},
+  prelude::*,
+};
+use parking_lot::RwLock;
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  ffi::OsStr,
+  iter,
+};
+
+#[derive(Debug)]
+struct Entry {
+  info: ValidPathInfo,
+  // paths that were only registered have no contents
+  nar: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+  paths: RwLock<BTreeMap<StorePath, Entry>>,
+}
+
+impl MemoryStore {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  fn insert(&self, info: ValidPathInfo, nar: Vec<u8>, repair: RepairFlag) {
+    let mut paths = self.paths.write();
+    if repair.repair() || !paths.contains_key(&info.store_path) {
+      paths.insert(
+        info.store_path.clone(),
+        Entry {
+          info,
+          nar: Some(nar),
+        },
+      );
+    }
+  }
+
+  fn read_nar(&self, path: &StorePath) -> Result<Vec<u8>> {
+    match self.paths.read().get(path) {
+      Some(Entry { nar: Some(nar), .. }) => Ok(nar.clone()),
+      Some(_) => bail!(
+        "contents of path {} are not available",
+        self.print_store_path(path)
+      ),
+      None => bail!("path {} is not valid", self.print_store_path(path)),
+    }
+  }
+}
+
+impl Store for MemoryStore {
+  fn store_path(&self) -> Cow<OsStr> {
+    Cow::Owned(settings().paths.nix_store.clone().into_os_string())
+  }
+
+  fn to_real_path<P: Borrow<StorePath>>(&self, path: P) -> Result<PathBuf> {
+    bail!("path {} only exists in memory", self.print_store_path(path))
+  }
+
+  fn read_derivation<P: Borrow<StorePath>>(&self, path: P) -> Result<Derivation> {
+    let path = path.borrow();
+    let contents = String::from_utf8(archive::read_contents(&self.read_nar(path)?[..])?)?;
+    Derivation::parse(self, &contents, &Derivation::name_from_path(path)?)
+  }
+
+  fn get_path_info<P: Borrow<StorePath>>(&self, path: P) -> Result<Option<Rc<dyn PathInfo>>> {
+    Ok(
+      self
+        .paths
+        .read()
+        .get(path.borrow())
+        .map(|e| Rc::new(e.info.clone()) as Rc<dyn PathInfo>),
+    )
+  }
+
+  fn register_valid_paths<I: IntoIterator<Item = ValidPathInfo>>(&self, infos: I) -> Result<()> {
+    let mut paths = self.paths.write();
+    for info in infos {
+      match paths.get_mut(&info.store_path) {
+        Some(entry) => entry.info = info,
+        None => {
+          paths.insert(info.store_path.clone(), Entry { info, nar: None });
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Nothing is ever garbage collected.
+  fn add_temp_root(&self, _: &StorePath) -> Result<()> {
+    Ok(())
+  }
+
+  fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    Ok(
+      self
+        .paths
+        .read()
+        .values()
+        .filter(|e| e.info.references.contains(path))
+        .map(|e| e.info.store_path.clone())
+        .collect(),
+    )
+  }
+
+  fn add_text_to_store<I: IntoIterator<Item = StorePath>>(
+    &self,
+    name: &str,
+    contents: &str,
+    references: I,
+    repair: RepairFlag,
+  ) -> Result<StorePath> {
+    let references = references.into_iter().collect::<BTreeSet<_>>();
+    let hash = Hash::hash_str(contents, HashType::SHA256);
+    let dest_path = self.make_text_path(name, &hash, references.clone())?;
+
+    let nar = archive::dump_to_bytes(contents.len(), contents.as_bytes())?;
+    let mut info = ValidPathInfo::new(dest_path.clone(), Hash::hash_bytes(&nar, HashType::SHA256));
+    info.references = references;
+    info.nar_size = Some(nar.len() as u64);
+    info.content_addressed = Some(text_ca(&hash));
+    self.insert(info, nar, repair);
+
+    Ok(dest_path)
+  }
+
+  fn add_to_store_from_source<I: PathInfo, R: Read>(
+    &self,
+    info: I,
+    source: R,
+    repair: RepairFlag,
+    _: CheckSigsFlag,
+  ) -> Result<()> {
+    let mut nar = vec![];
+    archive::copy_nar(source, &mut nar)?;
+
+    let nar_hash = info.nar_hash();
+    ensure!(
+      Hash::hash_bytes(&nar, nar_hash.type_()) == *nar_hash,
+      "hash mismatch importing path {}",
+      self.print_store_path(info.store_path())
+    );
+
+    let mut info = info.to_valid_path_info();
+    info.nar_size = Some(nar.len() as u64);
+    self.insert(info, nar, repair);
+    Ok(())
+  }
+
+  fn add_to_store_from_path(
+    &self,
+    name: &str,
+    path: &Path,
+    ingest_method: FileIngestionMethod,
+    hash_type: HashType,
+    filter: &PathFilter,
+    repair: RepairFlag,
+  ) -> Result<StorePath> {
+    let mut nar = vec![];
+    archive::dump_path(path, &mut nar, filter)?;
+
+    let hash = match ingest_method {
+      FileIngestionMethod::Recursive => Hash::hash_bytes(&nar, hash_type),
+      FileIngestionMethod::Flat => Hash::hash_file(path, hash_type)?.0,
+    };
+    let dest_path =
+      self.make_fixed_output_path(ingest_method, &hash, name, iter::empty(), false)?;
+
+    let mut info = ValidPathInfo::new(dest_path.clone(), Hash::hash_bytes(&nar, HashType::SHA256));
+    info.nar_size = Some(nar.len() as u64);
+    info.content_addressed = Some(fixed_output_ca(
+      ingest_method == FileIngestionMethod::Recursive,
+      &hash,
+    ));
+    self.insert(info, nar, repair);
+
+    Ok(dest_path)
+  }
+
+  fn nar_from_path<W: Write>(&self, path: &StorePath, mut sink: W) -> Result<()> {
+    sink.write_all(&self.read_nar(path)?)?;
+    Ok(())
+  }
+
+  fn add_signatures(&self, path: &StorePath, sigs: &BTreeSet<String>) -> Result<()> {
+    let mut paths = self.paths.write();
+    let entry = paths
+      .get_mut(path)
+      .ok_or_else(|| anyhow!("path {} is not valid", self.print_store_path(path)))?;
+    entry.info.signatures.extend(sigs.iter().cloned());
+    Ok(())
+  }
+
+  fn build_paths(&self, _: Vec<StorePathWithOutputs>) -> Result<()> {
+    bail!("paths can't be built in a memory store")
+  }
+}
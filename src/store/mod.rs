@@ -6,9 +6,22 @@ use std::{
   fmt::{Debug, Display},
 };
 
+mod any;
+pub mod binary_cache;
+mod dummy;
+mod export;
+pub mod graph;
 mod local;
+mod memory;
+mod remote;
 
+pub use any::*;
+pub use binary_cache::{BinaryCacheStore, HttpBinaryCacheStore, LocalBinaryCacheStore};
+pub use dummy::*;
+pub use export::*;
 pub use local::*;
+pub use memory::*;
+pub use remote::*;
 
 #[allow(clippy::needless_lifetimes)] // clippy pls
 pub(crate) fn show_path<'a>(i: &'a OsStr) -> impl Display + 'a {
@@ -41,9 +54,172 @@ pub enum FileIngestionMethod {
 
 #[derive(Copy, Clone, Debug, Default)]
 pub struct ClosureOpts {
-  backwards: bool,
-  include_outputs: bool,
-  include_derivers: bool,
+  /// Follow referrers instead of references.
+  pub backwards: bool,
+  /// Include the outputs of derivations in the closure.
+  pub include_outputs: bool,
+  /// Include the derivers of paths in the closure.
+  pub include_derivers: bool,
+}
+
+/// Sorts `paths` so that each path comes before all of the paths it
+/// references, i.e. referrers first. References outside of `paths` are
+/// ignored.
+pub fn topo_sort<F: FnMut(&StorePath) -> Result<BTreeSet<StorePath>>>(
+  paths: &BTreeSet<StorePath>,
+  mut references: F,
+) -> Result<Vec<StorePath>> {
+  fn visit<F: FnMut(&StorePath) -> Result<BTreeSet<StorePath>>>(
+    path: &StorePath,
+    paths: &BTreeSet<StorePath>,
+    references: &mut F,
+    visited: &mut BTreeSet<StorePath>,
+    parents: &mut BTreeSet<StorePath>,
+    sorted: &mut Vec<StorePath>,
+  ) -> Result<()> {
+    if parents.contains(path) {
+      bail!("cycle detected in the references of `{}'", path);
+    }
+    if !visited.insert(path.clone()) {
+      return Ok(());
+    }
+    parents.insert(path.clone());
+    for r in references(path)? {
+      if &r != path && paths.contains(&r) {
+        visit(&r, paths, references, visited, parents, sorted)?;
+      }
+    }
+    parents.remove(path);
+    sorted.push(path.clone());
+    Ok(())
+  }
+
+  let mut sorted = Vec::with_capacity(paths.len());
+  let mut visited = BTreeSet::new();
+  let mut parents = BTreeSet::new();
+  for path in paths {
+    visit(
+      path,
+      paths,
+      &mut references,
+      &mut visited,
+      &mut parents,
+      &mut sorted,
+    )?;
+  }
+  sorted.reverse();
+  Ok(sorted)
+}
+
+/// Copies the closures of `paths` from `src` to `dst`, skipping paths that
+/// are already valid in `dst`. Paths are copied in parallel, each one as soon
+/// as all of its references are valid in `dst`.
+pub fn copy_paths<S: Store, D: Store>(
+  src: &S,
+  dst: &D,
+  paths: &BTreeSet<StorePath>,
+  check_sigs: CheckSigsFlag,
+) -> Result<()> {
+  let mut closure = BTreeSet::new();
+  for path in paths {
+    src.compute_closure(path, &mut closure, Default::default())?;
+  }
+
+  let mut missing = BTreeSet::new();
+  for path in closure {
+    if !dst.is_valid_path(&path)? {
+      missing.insert(path);
+    }
+  }
+
+  let mut infos = BTreeMap::new();
+  // the number of references each path is still waiting for
+  let mut blocked_on = BTreeMap::new();
+  let mut referrers = BTreeMap::<_, Vec<_>>::new();
+  for path in &missing {
+    let info = src
+      .get_path_info(path)?
+      .ok_or_else(|| anyhow!("path {} is not valid", src.print_store_path(path)))?
+      .to_valid_path_info();
+    let refs = info
+      .references
+      .iter()
+      .filter(|r| *r != path && missing.contains(*r))
+      .collect::<Vec<_>>();
+    for r in &refs {
+      referrers
+        .entry((*r).clone())
+        .or_default()
+        .push(path.clone());
+    }
+    blocked_on.insert(path.clone(), refs.len());
+    infos.insert(path.clone(), info);
+  }
+
+  let (ready_send, ready_recv) = crossbeam::channel::unbounded::<ValidPathInfo>();
+  let (done_send, done_recv) = crossbeam::channel::unbounded();
+
+  crossbeam::scope(|s| {
+    for _ in 0..num_cpus::get().min(missing.len()) {
+      let ready_recv = ready_recv.clone();
+      let done_send = done_send.clone();
+      s.spawn(move |_| {
+        for info in ready_recv {
+          let path = info.store_path.clone();
+          let result = copy_path(src, dst, info, check_sigs);
+          if done_send.send((path, result)).is_err() {
+            break;
+          }
+        }
+      });
+    }
+    drop(done_send);
+
+    for (path, _) in blocked_on.iter().filter(|(_, n)| **n == 0) {
+      ready_send.send(infos.remove(path).unwrap())?;
+    }
+
+    let mut remaining = missing.len();
+    let result = loop {
+      if remaining == 0 {
+        break Ok(());
+      }
+      let (path, result) = done_recv.recv()?;
+      if let Err(e) = result {
+        break Err(e);
+      }
+      remaining -= 1;
+      for r in referrers.get(&path).into_iter().flatten() {
+        let n = blocked_on.get_mut(r).unwrap();
+        *n -= 1;
+        if *n == 0 {
+          ready_send.send(infos.remove(r).unwrap())?;
+        }
+      }
+    };
+
+    // don't start copying anything else after a failure
+    while ready_recv.try_recv().is_ok() {}
+    drop(ready_send);
+    result
+  })
+  .unwrap()
+}
+
+fn copy_path<S: Store, D: Store>(
+  src: &S,
+  dst: &D,
+  info: ValidPathInfo,
+  check_sigs: CheckSigsFlag,
+) -> Result<()> {
+  let path = info.store_path.clone();
+  info!("copying {}", src.print_store_path(&path));
+  crossbeam::scope(|s| {
+    let source = make_pipe(s, |sink| src.nar_from_path(&path, sink));
+    dst.add_to_store_from_source(info, source, RepairFlag::NoRepair, check_sigs)
+  })
+  .unwrap()
+  .with_context(|| format!("while copying {}", src.print_store_path(&path)))
 }
 
 pub trait Store: Send + Sync + Debug {
@@ -71,6 +247,37 @@ pub trait Store: Send + Sync + Debug {
     StorePath::new(path.as_ref(), self.store_path().as_ref())
   }
 
+  fn to_store_path(&self, path: &Path) -> Result<StorePath> {
+    let base_name = path
+      .strip_prefix(&*self.store_path())
+      .ok()
+      .and_then(|x| x.iter().next())
+      .ok_or_else(|| crate::path::Error::NotInStore(path.into()))?;
+    StorePath::from_base_name(
+      base_name
+        .to_str()
+        .ok_or_else(|| crate::path::Error::InvalidFilepath(path.into()))?,
+    )
+  }
+
+  /// The store path that `path` leads to, following symlinks only until one
+  /// points into the store. Unlike canonicalizing, this works when the store
+  /// isn't at its logical location, e.g. in a chroot store.
+  fn follow_links_to_store_path(&self, path: &Path) -> Result<StorePath> {
+    let mut path = std::env::current_dir()?.join(path);
+    while !self.is_in_store(&path) {
+      let target = match fs::read_link(&path) {
+        Ok(target) => target,
+        Err(_) => break,
+      };
+      path = match path.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+      };
+    }
+    self.to_store_path(&path)
+  }
+
   fn parse_path_with_outputs(&self, input: &str) -> Result<StorePathWithOutputs> {
     let (path, outputs) = if let Some(n) = input.find('!') {
       (
@@ -118,6 +325,27 @@ pub trait Store: Send + Sync + Debug {
     bail!("not supported by this store backend")
   }
 
+  fn query_referrers(&self, _path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    bail!("not supported by this store backend")
+  }
+
+  /// The valid derivations that have `path` as an output.
+  fn query_valid_derivers(&self, _path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    bail!("not supported by this store backend")
+  }
+
+  /// The outputs of the derivation `path`, by name.
+  fn query_derivation_outputs(&self, path: &StorePath) -> Result<BTreeMap<String, StorePath>> {
+    Ok(
+      self
+        .read_derivation(path)?
+        .outputs
+        .into_iter()
+        .map(|(id, out)| (id, out.path))
+        .collect(),
+    )
+  }
+
   fn make_type<I: IntoIterator<Item = StorePath>>(
     &self,
     mut s: String,
@@ -291,6 +519,26 @@ pub trait Store: Send + Sync + Debug {
     repair: RepairFlag,
   ) -> Result<StorePath>;
 
+  /// Write the NAR serialisation of `path` to `sink`.
+  #[allow(unused_variables)]
+  fn nar_from_path<W: Write>(&self, path: &StorePath, sink: W) -> Result<()> {
+    bail!("not supported by this store backend")
+  }
+
+  /// Add `sigs` to the signatures of the already valid path `path`.
+  #[allow(unused_variables)]
+  fn add_signatures(&self, path: &StorePath, sigs: &BTreeSet<String>) -> Result<()> {
+    bail!("not supported by this store backend")
+  }
+
+  /// Sign `info` with each of the keys in `secret-key-files`.
+  fn sign_path_info(&self, info: &mut ValidPathInfo) -> Result<()> {
+    for file in &settings().secret_key_files {
+      crate::signing::sign_path_info(self, info, &crate::signing::cached_secret_key(file)?)?;
+    }
+    Ok(())
+  }
+
   #[allow(unused_variables)]
   fn build_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
     bail!(
@@ -299,22 +547,131 @@ pub trait Store: Send + Sync + Debug {
     )
   }
 
+  /// Rebuild `paths`, whose outputs must already be valid, and check that the
+  /// results are identical.
+  #[allow(unused_variables)]
+  fn check_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
+    bail!(
+      "store backend {} does not support checking builds",
+      self.store_path().to_string_lossy()
+    )
+  }
+
   fn compute_closure(
     &self,
     path: &StorePath,
     closure: &mut BTreeSet<StorePath>,
     options: ClosureOpts,
-  ) -> Result<()>;
+  ) -> Result<()> {
+    if !closure.insert(path.clone()) {
+      return Ok(());
+    }
+
+    let info = self
+      .get_path_info(path)?
+      .ok_or_else(|| anyhow!("path {} is invalid", self.print_store_path(path)))?;
+
+    let mut next = BTreeSet::new();
+
+    if options.backwards {
+      next.extend(self.query_referrers(path)?);
+
+      // derivations that have this path as an output
+      if options.include_outputs {
+        if let Some(d) = info.deriver() {
+          if self.is_valid_path(d)? && self.read_derivation(d)?.out_paths().any(|x| x == path) {
+            next.insert(d.clone());
+          }
+        }
+      }
+
+      // outputs that were built by this derivation
+      if options.include_derivers && path.is_derivation() {
+        for out in self.read_derivation(path)?.out_paths() {
+          if let Some(out_info) = self.get_path_info(out)? {
+            if out_info.deriver() == Some(path) {
+              next.insert(out.clone());
+            }
+          }
+        }
+      }
+    } else {
+      next.extend(info.references().iter().cloned());
+
+      if options.include_outputs && path.is_derivation() {
+        for out in self.read_derivation(path)?.out_paths() {
+          if self.is_valid_path(out)? {
+            next.insert(out.clone());
+          }
+        }
+      }
+
+      if options.include_derivers {
+        if let Some(d) = info.deriver() {
+          if self.is_valid_path(d)? {
+            next.insert(d.clone());
+          }
+        }
+      }
+    }
+
+    for r in next {
+      if &r != path {
+        self.compute_closure(&r, closure, options)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn topo_sort_paths(&self, paths: &BTreeSet<StorePath>) -> Result<Vec<StorePath>> {
+    topo_sort(paths, |path| {
+      Ok(
+        self
+          .get_path_info(path)?
+          .map_or_else(Default::default, |x| x.references().clone()),
+      )
+    })
+  }
+
+  fn log_dir(&self) -> Cow<Path> {
+    Cow::Owned(settings().paths.nix_log_dir.clone())
+  }
 
   fn logfile_of(&self, path: &StorePath) -> PathBuf {
     let mut log_part0 = path.to_string();
     let log_part1 = log_part0.split_off(2);
 
-    settings()
-      .paths
-      .nix_log_dir
-      .join("drvs")
-      .join(log_part0)
-      .join(log_part1)
+    self.log_dir().join("drvs").join(log_part0).join(log_part1)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive;
+
+  #[test]
+  fn test_copy_paths() -> Result<()> {
+    Settings::init_for_tests();
+    let src = MemoryStore::new();
+    let dst = MemoryStore::new();
+    let dep = src.add_text_to_store("dep", "foo", None, RepairFlag::NoRepair)?;
+    let top = src.add_text_to_store("top", "bar", Some(dep.clone()), RepairFlag::NoRepair)?;
+
+    copy_paths(
+      &src,
+      &dst,
+      &maplit::btreeset! { top.clone() },
+      CheckSigsFlag::CheckSigs,
+    )?;
+
+    assert!(dst.is_valid_path(&dep)?);
+    let info = dst.get_path_info(&top)?.unwrap();
+    assert_eq!(info.references(), &maplit::btreeset! { dep });
+    let mut nar = vec![];
+    dst.nar_from_path(&top, &mut nar)?;
+    assert_eq!(archive::read_contents(&nar[..])?, b"bar");
+    Ok(())
   }
 }
@@ -0,0 +1,376 @@
+//! A store that forwards every operation to a Nix daemon, for users who
+//! can't write to the store themselves.
+
+use super::{CheckSigsFlag, FileIngestionMethod, RepairFlag};
+use crate::{
+  archive::{self, PathFilter},
+  daemon::*,
+  prelude::*,
+};
+use parking_lot::{Mutex, MutexGuard};
+use std::{
+  collections::BTreeSet,
+  ffi::OsStr,
+  fmt,
+  io::{BufReader, BufWriter},
+  os::unix::net::UnixStream,
+};
+
+struct Connection {
+  from: BufReader<UnixStream>,
+  to: archive::Sink<BufWriter<UnixStream>>,
+}
+
+impl Connection {
+  fn open(socket_path: &Path) -> Result<Self> {
+    let stream = UnixStream::connect(socket_path)
+      .with_context(|| format!("cannot connect to daemon at `{}'", socket_path.display()))?;
+    let mut conn = Self {
+      from: BufReader::new(stream.try_clone()?),
+      to: archive::Sink::new(BufWriter::new(stream)),
+    };
+
+    conn.to.write_usize(WORKER_MAGIC_1)?;
+    conn.to.flush()?;
+    ensure!(
+      read_num(&mut conn.from)? == WORKER_MAGIC_2,
+      "protocol mismatch"
+    );
+
+    let daemon_version = read_num(&mut conn.from)?;
+    ensure!(
+      protocol_major(daemon_version) == protocol_major(PROTOCOL_VERSION)
+        && protocol_minor(daemon_version) >= protocol_minor(PROTOCOL_VERSION),
+      "the Nix daemon version {}.{} is too old",
+      daemon_version >> 8,
+      protocol_minor(daemon_version)
+    );
+
+    conn.to.write_usize(PROTOCOL_VERSION)?;
+    // no CPU affinity
+    conn.to.write_usize(0)?;
+    // obsolete reserveSpace flag
+    conn.to.write_usize(0)?;
+    conn.process_stderr(None)?;
+
+    conn.set_options()?;
+
+    Ok(conn)
+  }
+
+  /// Pass our settings on to the daemon, which builds on our behalf.
+  fn set_options(&mut self) -> Result<()> {
+    let settings = settings();
+    self.to.write_usize(Op::SetOptions as usize)?;
+    self.to.write_usize(settings.keep_failed as usize)?;
+    self.to.write_usize(settings.keep_going as usize)?;
+    self.to.write_usize(settings.try_fallback as usize)?;
+    // verbosity
+    self.to.write_usize(0)?;
+    self.to.write_usize(settings.max_build_jobs)?;
+    self
+      .to
+      .write_usize(settings.max_silent_time.map_or(0, |t| t.as_secs() as usize))?;
+    // obsolete useBuildHook flag
+    self.to.write_usize(1)?;
+    // build output is shown at the error level when it's verbose, and
+    // otherwise at the most verbose one
+    self
+      .to
+      .write_usize(if settings.verbose_build { 0 } else { 7 })?;
+    // obsolete logType and printBuildTrace
+    self.to.write_usize(0)?;
+    self.to.write_usize(0)?;
+    self.to.write_usize(settings.build_cores)?;
+    self.to.write_usize(settings.use_substitutes as usize)?;
+
+    let overrides = settings.overridden();
+    self.to.write_usize(overrides.len())?;
+    for (name, value) in overrides {
+      self.to.write_tag(name)?;
+      self.to.write_tag(value)?;
+    }
+    self.process_stderr(None)
+  }
+
+  /// Handle the daemon's log messages and data requests until it signals
+  /// that the current operation is done. Data requested by the daemon is
+  /// read from `source`.
+  fn process_stderr(&mut self, mut source: Option<&mut dyn Read>) -> Result<()> {
+    self.to.flush()?;
+    loop {
+      match read_num(&mut self.from)? {
+        STDERR_LAST => return Ok(()),
+        STDERR_ERROR => {
+          let msg = read_string(&mut self.from)?;
+          // exit status, which is of no use to us
+          read_num(&mut self.from)?;
+          bail!("{}", msg)
+        }
+        STDERR_NEXT => {
+          let msg = read_string(&mut self.from)?;
+          info!("{}", msg.trim_end_matches('\n'));
+        }
+        STDERR_READ => {
+          let source = source
+            .as_mut()
+            .ok_or_else(|| anyhow!("no source available for the daemon to read from"))?;
+          let mut buf = vec![0; read_num(&mut self.from)?];
+          let len = source.read(&mut buf)?;
+          self.to.write_tag(&buf[..len])?;
+          self.to.flush()?;
+        }
+        STDERR_START_ACTIVITY => {
+          // id, level, type
+          for _ in 0..3 {
+            read_num(&mut self.from)?;
+          }
+          let text = read_string(&mut self.from)?;
+          self.read_fields()?;
+          // parent
+          read_num(&mut self.from)?;
+          if !text.is_empty() {
+            info!("{}", text);
+          }
+        }
+        STDERR_STOP_ACTIVITY => {
+          read_num(&mut self.from)?;
+        }
+        STDERR_RESULT => {
+          // id, type
+          read_num(&mut self.from)?;
+          read_num(&mut self.from)?;
+          self.read_fields()?;
+        }
+        x => bail!("got unsupported field {:#x} from the Nix daemon", x),
+      }
+    }
+  }
+
+  fn read_fields(&mut self) -> Result<()> {
+    for _ in 0..read_num(&mut self.from)? {
+      match read_num(&mut self.from)? {
+        0 => {
+          read_num(&mut self.from)?;
+        }
+        1 => {
+          read_string(&mut self.from)?;
+        }
+        x => bail!("unsupported logger field type {}", x),
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A connection to the daemon listening on a Unix domain socket. Operations
+/// are serialised over a single connection.
+pub struct RemoteStore {
+  socket_path: PathBuf,
+  conn: Mutex<Connection>,
+}
+
+impl fmt::Debug for RemoteStore {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("RemoteStore")
+      .field("socket_path", &self.socket_path)
+      .finish()
+  }
+}
+
+impl RemoteStore {
+  pub fn open<P: Into<PathBuf>>(socket_path: P) -> Result<Self> {
+    let socket_path = socket_path.into();
+    Ok(Self {
+      conn: Mutex::new(Connection::open(&socket_path)?),
+      socket_path,
+    })
+  }
+
+  /// Lock the connection and start `op`.
+  fn start_op(&self, op: Op) -> Result<MutexGuard<Connection>> {
+    let mut conn = self.conn.lock();
+    conn.to.write_usize(op as usize)?;
+    Ok(conn)
+  }
+
+  fn write_path(&self, conn: &mut Connection, path: &StorePath) -> io::Result<()> {
+    conn.to.write_tag(self.print_store_path(path))
+  }
+
+  fn build_paths_with_mode(&self, paths: Vec<StorePathWithOutputs>, mode: BuildMode) -> Result<()> {
+    let mut conn = self.start_op(Op::BuildPaths)?;
+    write_strings(
+      &mut conn.to,
+      paths.iter().map(|p| {
+        let path = self.print_store_path(&p.path);
+        if p.outputs.is_empty() {
+          path
+        } else {
+          format!("{}!{}", path, itertools::join(&p.outputs, ","))
+        }
+      }),
+    )?;
+    conn.to.write_usize(mode as usize)?;
+    conn.process_stderr(None)?;
+    read_num(&mut conn.from)?;
+    Ok(())
+  }
+}
+
+impl Store for RemoteStore {
+  fn store_path(&self) -> Cow<OsStr> {
+    Cow::Owned(settings().paths.nix_store.clone().into_os_string())
+  }
+
+  fn get_path_info<P: Borrow<StorePath>>(&self, path: P) -> Result<Option<Rc<dyn PathInfo>>> {
+    let path = path.borrow();
+    let mut conn = self.start_op(Op::QueryPathInfo)?;
+    self.write_path(&mut conn, path)?;
+    conn.process_stderr(None)?;
+    if !read_bool(&mut conn.from)? {
+      return Ok(None);
+    }
+    Ok(Some(Rc::new(read_path_info(
+      self,
+      &mut conn.from,
+      path.clone(),
+    )?)))
+  }
+
+  fn is_valid_path(&self, path: &StorePath) -> Result<bool> {
+    let mut conn = self.start_op(Op::IsValidPath)?;
+    self.write_path(&mut conn, path)?;
+    conn.process_stderr(None)?;
+    read_bool(&mut conn.from)
+  }
+
+  fn register_valid_paths<I: IntoIterator<Item = ValidPathInfo>>(&self, _: I) -> Result<()> {
+    bail!("paths can't be registered through the Nix daemon")
+  }
+
+  fn add_temp_root(&self, path: &StorePath) -> Result<()> {
+    let mut conn = self.start_op(Op::AddTempRoot)?;
+    self.write_path(&mut conn, path)?;
+    conn.process_stderr(None)?;
+    read_num(&mut conn.from)?;
+    Ok(())
+  }
+
+  fn query_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    let mut conn = self.start_op(Op::QueryReferrers)?;
+    self.write_path(&mut conn, path)?;
+    conn.process_stderr(None)?;
+    read_store_paths(self, &mut conn.from)
+  }
+
+  fn query_valid_derivers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
+    let mut conn = self.start_op(Op::QueryValidDerivers)?;
+    self.write_path(&mut conn, path)?;
+    conn.process_stderr(None)?;
+    read_store_paths(self, &mut conn.from)
+  }
+
+  fn add_text_to_store<I: IntoIterator<Item = StorePath>>(
+    &self,
+    name: &str,
+    contents: &str,
+    references: I,
+    repair: RepairFlag,
+  ) -> Result<StorePath> {
+    ensure!(
+      !repair.repair(),
+      "repairing is not supported when building through the Nix daemon"
+    );
+    let references = references.into_iter().collect::<BTreeSet<_>>();
+    let mut conn = self.start_op(Op::AddTextToStore)?;
+    conn.to.write_tag(name)?;
+    conn.to.write_tag(contents)?;
+    write_store_paths(self, &mut conn.to, &references)?;
+    conn.process_stderr(None)?;
+    read_store_path(self, &mut conn.from)
+  }
+
+  fn add_to_store_from_source<I: PathInfo, R: Read>(
+    &self,
+    info: I,
+    mut source: R,
+    repair: RepairFlag,
+    check_sigs: CheckSigsFlag,
+  ) -> Result<()> {
+    ensure!(
+      info.nar_hash().type_() == HashType::SHA256,
+      "the Nix daemon only accepts SHA-256 NAR hashes"
+    );
+    let mut conn = self.start_op(Op::AddToStoreNar)?;
+    self.write_path(&mut conn, info.store_path())?;
+    write_path_info(
+      self,
+      &mut conn.to,
+      &info.to_valid_path_info(),
+      protocol_minor(PROTOCOL_VERSION),
+    )?;
+    conn.to.write_usize(repair.repair() as usize)?;
+    conn
+      .to
+      .write_usize((check_sigs == CheckSigsFlag::NoCheckSigs) as usize)?;
+    conn.process_stderr(Some(&mut source as &mut dyn Read))
+  }
+
+  fn add_to_store_from_path(
+    &self,
+    name: &str,
+    path: &Path,
+    ingest_method: FileIngestionMethod,
+    hash_type: HashType,
+    filter: &PathFilter,
+    repair: RepairFlag,
+  ) -> Result<StorePath> {
+    ensure!(
+      !repair.repair(),
+      "repairing is not supported when building through the Nix daemon"
+    );
+    let recursive = ingest_method == FileIngestionMethod::Recursive;
+    let mut conn = self.start_op(Op::AddToStore)?;
+    conn.to.write_tag(name)?;
+    // a recursive SHA-256 hash is the historical default, which older daemons
+    // expect to be signalled by leaving `fixed' unset
+    conn
+      .to
+      .write_usize(!(recursive && hash_type == HashType::SHA256) as usize)?;
+    conn.to.write_usize(recursive as usize)?;
+    conn.to.write_tag(hash_type.to_string())?;
+    archive::dump_path(path, &mut conn.to, filter)?;
+    conn.process_stderr(None)?;
+    read_store_path(self, &mut conn.from)
+  }
+
+  fn nar_from_path<W: Write>(&self, path: &StorePath, sink: W) -> Result<()> {
+    let mut conn = self.start_op(Op::NarFromPath)?;
+    self.write_path(&mut conn, path)?;
+    conn.process_stderr(None)?;
+    archive::copy_nar(&mut conn.from, sink)
+  }
+
+  fn add_signatures(&self, path: &StorePath, sigs: &BTreeSet<String>) -> Result<()> {
+    let mut conn = self.start_op(Op::AddSignatures)?;
+    self.write_path(&mut conn, path)?;
+    write_strings(&mut conn.to, sigs)?;
+    conn.process_stderr(None)?;
+    read_num(&mut conn.from)?;
+    Ok(())
+  }
+
+  /// The daemon signs paths it adds with its own keys.
+  fn sign_path_info(&self, _: &mut ValidPathInfo) -> Result<()> {
+    Ok(())
+  }
+
+  fn build_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
+    self.build_paths_with_mode(paths, BuildMode::Normal)
+  }
+
+  fn check_paths(&self, paths: Vec<StorePathWithOutputs>) -> Result<()> {
+    self.build_paths_with_mode(paths, BuildMode::Check)
+  }
+}
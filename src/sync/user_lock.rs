@@ -1,6 +1,13 @@
 use crate::{prelude::*, sync::fs_lock::*};
 use fs::File;
-use unix::unistd::{Gid, Uid};
+use unix::{
+  errno::Errno,
+  sys::{
+    signal::{kill, Signal},
+    wait::{waitpid, WaitStatus},
+  },
+  unistd::{self, ForkResult, Gid, Pid, Uid},
+};
 use users::os::unix::GroupExt;
 
 #[derive(Debug)]
@@ -71,8 +78,40 @@ impl UserLock {
     Ok(None)
   }
 
+  /// Kill every process running as this user, including any that escaped
+  /// the builder's process group.
   pub fn kill(&self) -> Result<()> {
-    Ok(())
+    debug!("killing all processes running under uid {}", self.uid);
+
+    // `kill(-1)` signals every process we're allowed to, so it has to be sent
+    // from a process that has dropped to the build user's privileges
+    match unistd::fork().context("can't create a child process")? {
+      ForkResult::Child => {
+        // only async-signal-safe calls from here on, since other threads may
+        // have held locks when we forked; `_exit` skips atexit handlers and
+        // buffered stdio that belong to the parent
+        if unistd::setuid(self.uid).is_err() {
+          unsafe { libc::_exit(1) };
+        }
+        loop {
+          match kill(Pid::from_raw(-1), Signal::SIGKILL) {
+            Ok(()) => {}
+            Err(e) if e.as_errno() == Some(Errno::ESRCH) => unsafe { libc::_exit(0) },
+            Err(_) => unsafe { libc::_exit(1) },
+          }
+        }
+      }
+      ForkResult::Parent { child } => {
+        let status = waitpid(child, None)?;
+        ensure!(
+          status == WaitStatus::Exited(child, 0),
+          "cannot kill processes for uid {}: {}",
+          self.uid,
+          show_status(status)
+        );
+        Ok(())
+      }
+    }
   }
 }
 
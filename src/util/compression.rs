@@ -0,0 +1,98 @@
+use anyhow::*;
+use std::{
+  io::{self, Read, Write},
+  str::FromStr,
+};
+
+/// Compression methods that can be used for NARs in a binary cache.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Display)]
+pub enum Compression {
+  #[display(fmt = "none")]
+  None,
+  #[display(fmt = "xz")]
+  Xz,
+  #[display(fmt = "bzip2")]
+  Bzip2,
+  #[display(fmt = "zstd")]
+  Zstd,
+}
+
+impl FromStr for Compression {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    Ok(match s {
+      "none" => Self::None,
+      "xz" => Self::Xz,
+      "bzip2" => Self::Bzip2,
+      "zstd" => Self::Zstd,
+      x => bail!("unknown compression method `{}'", x),
+    })
+  }
+}
+
+impl Compression {
+  /// The file extension conventionally used for data compressed with this
+  /// method, including the leading dot.
+  pub fn extension(self) -> &'static str {
+    match self {
+      Self::None => "",
+      Self::Xz => ".xz",
+      Self::Bzip2 => ".bz2",
+      Self::Zstd => ".zst",
+    }
+  }
+
+  pub fn decompress<'a, R: Read + 'a>(self, source: R) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match self {
+      Self::None => Box::new(source),
+      Self::Xz => Box::new(xz2::read::XzDecoder::new(source)),
+      Self::Bzip2 => Box::new(bzip2::read::BzDecoder::new(source)),
+      Self::Zstd => Box::new(zstd::stream::read::Decoder::new(source)?),
+    })
+  }
+
+  /// Compress everything in `source` into `sink`, returning the sink once the
+  /// compressed stream has been finished.
+  pub fn compress<R: Read, W: Write>(self, mut source: R, mut sink: W) -> io::Result<W> {
+    match self {
+      Self::None => {
+        io::copy(&mut source, &mut sink)?;
+        Ok(sink)
+      }
+      Self::Xz => {
+        let mut encoder = xz2::write::XzEncoder::new(sink, 6);
+        io::copy(&mut source, &mut encoder)?;
+        encoder.finish()
+      }
+      Self::Bzip2 => {
+        let mut encoder = bzip2::write::BzEncoder::new(sink, bzip2::Compression::default());
+        io::copy(&mut source, &mut encoder)?;
+        encoder.finish()
+      }
+      Self::Zstd => {
+        let mut encoder = zstd::stream::write::Encoder::new(sink, 0)?;
+        io::copy(&mut source, &mut encoder)?;
+        encoder.finish()
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use proptest::*;
+
+  proptest! {
+    #[test]
+    fn roundtrip(s: Vec<u8>) {
+      for method in &[Compression::None, Compression::Xz, Compression::Bzip2, Compression::Zstd] {
+        let compressed = method.compress(s.as_slice(), vec![]).unwrap();
+        let mut decompressed = vec![];
+        method.decompress(compressed.as_slice()).unwrap().read_to_end(&mut decompressed).unwrap();
+        assert_eq!(s, decompressed);
+      }
+    }
+  }
+}
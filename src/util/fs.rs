@@ -38,6 +38,26 @@ pub fn rm_rf<P: AsRef<Path>>(path: P) -> Result<()> {
   }
 }
 
+/// Recursively change the owner of `path`, without following symlinks.
+pub fn chown_r<P: AsRef<Path>>(path: P, uid: Uid, gid: Gid) -> Result<()> {
+  let path = path.as_ref();
+  fchownat(
+    None,
+    path,
+    Some(uid),
+    Some(gid),
+    FchownatFlags::NoFollowSymlink,
+  )
+  .with_context(|| format!("while changing ownership of path `{}'", path.display()))?;
+
+  if std::fs::symlink_metadata(path)?.is_dir() {
+    for entry in std::fs::read_dir(path)? {
+      chown_r(entry?.path(), uid, gid)?;
+    }
+  }
+  Ok(())
+}
+
 pub fn canonicalise_path_metadata<P: AsRef<Path>>(path: P, uid: Option<u32>) -> Result<()> {
   canonicalise_path_metadata_impl(path, uid, &mut HashSet::new())
 }
@@ -209,17 +229,12 @@ pub fn canonicalize_timestamp_and_permissions<P: AsRef<Path>>(
   Ok(())
 }
 
-pub fn optimise_path(path: &Path) -> Result<()> {
-  let mut stats = OptimiseStats::default();
-  optimise_path_impl(path, &mut HashSet::new(), &mut stats)?;
-  info!(
-    "{} files hard-linked, {} bytes freed",
-    stats.files_linked, stats.bytes_freed,
-  );
-  Ok(())
-}
-
-fn optimise_path_impl(
+/// Replace the files in `path` with hard links to identical files in the
+/// `.links` directory of `store_dir`, the real location of the store, adding
+/// them there if necessary. Files whose inodes are in `inodes` are known to be
+/// linked already and are skipped.
+pub fn optimise_path(
+  store_dir: &Path,
   path: &Path,
   inodes: &mut HashSet<u64>,
   stats: &mut OptimiseStats,
@@ -241,7 +256,7 @@ fn optimise_path_impl(
         debug!("path `{}' is already linked", this_path.display());
         continue;
       }
-      optimise_path_impl(&this_path, inodes, stats)?;
+      optimise_path(store_dir, &this_path, inodes, stats)?;
     }
     return Ok(());
   }
@@ -272,9 +287,7 @@ fn optimise_path_impl(
     file_hash.encode_with_type(Encoding::Base32)
   );
 
-  let link_path = settings()
-    .paths
-    .nix_store
+  let link_path = store_dir
     .join(".links")
     .join(file_hash.encode(Encoding::Base32));
 
@@ -325,38 +338,62 @@ fn optimise_path_impl(
     info!("linking `{}' to `{}'", path.display(), link_path.display());
 
     let parent = path.parent().unwrap();
-    let need_temp_permissions = parent != settings().paths.nix_store;
+    let need_temp_permissions = parent != store_dir;
 
-    if need_temp_permissions {
+    // make the parent read-only again however we leave this iteration
+    let _restore_parent = if need_temp_permissions {
       let meta = fs::metadata(parent)?;
       let mut perms = meta.permissions();
       perms.set_mode(perms.mode() | 0o700);
       fs::set_permissions(parent, perms)?;
-    }
+      Some(RunOnDrop::new(move || {
+        let restored = fs::symlink_metadata(parent)
+          .map_err(anyhow::Error::from)
+          .and_then(|meta| canonicalize_timestamp_and_permissions(parent, &meta));
+        if let Err(e) = restored {
+          warn!(
+            "unable to restore permissions of `{}': {:#}",
+            parent.display(),
+            e
+          );
+        }
+      }))
+    } else {
+      None
+    };
 
-    let temp_link = settings().paths.nix_store.join(format!(
+    let temp_link = store_dir.join(format!(
       ".tmp-link-{}-{}",
       std::process::id(),
       rand::random::<u32>()
     ));
 
-    fs::hard_link(&link_path, &temp_link)?;
-    fs::rename(&temp_link, path).with_context(|| {
-      format!(
-        "while trying to move `{}' to `{}'",
-        temp_link.display(),
-        path.display()
-      )
-    })?;
+    if let Err(e) = fs::hard_link(&link_path, &temp_link) {
+      if e.raw_os_error().map(Errno::from_i32) == Some(Errno::EMLINK) {
+        info!(
+          "`{}' has reached the maximum number of links",
+          link_path.display()
+        );
+        return Ok(());
+      }
+      return Err(e.into());
+    }
+    if let Err(e) = fs::rename(&temp_link, path) {
+      // don't leave a stray link behind to be found by the next run
+      let _ = fs::remove_file(&temp_link);
+      return Err(e).with_context(|| {
+        format!(
+          "while trying to move `{}' to `{}'",
+          temp_link.display(),
+          path.display()
+        )
+      });
+    }
 
     stats.files_linked += 1;
     stats.bytes_freed += info.size();
     stats.blocks_freed += info.blocks();
 
-    if need_temp_permissions {
-      canonicalize_timestamp_and_permissions(parent, &fs::symlink_metadata(parent)?)?;
-    }
-
     break;
   }
 
@@ -8,6 +8,7 @@ use std::{
 };
 
 pub mod base32;
+pub mod compression;
 mod fs;
 mod io;
 pub mod pid;